@@ -6,13 +6,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut type_config: prost_build::Config = prost_build::Config::new();
     type_config.enable_type_names();
-    type_config.type_name_domain(&["."], "type.googleapis.com");
+    type_config.type_name_domain(["."], "type.googleapis.com");
 
     tonic_build::configure()
         .file_descriptor_set_path(file_descriptor)
         .compile_protos_with_config(
             type_config,
             &[
+                "proto/google/rpc/error_details.proto",
+                "proto/google/rpc/status.proto",
                 "proto/resourceusage/resourceusage.proto",
                 "proto/runner/runner.proto",
             ],
@@ -0,0 +1,189 @@
+use std::io::Result;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::json::Value;
+use crate::resource::ExitResources;
+
+/// One finished job, written as a single JSON line
+#[derive(Debug)]
+pub(crate) struct AuditEntry<'a> {
+    pub pid: u32,
+    pub processor: u32,
+    pub command: &'a [String],
+    pub working_directory: &'a str,
+    /// `None` if waiting for the child failed
+    pub exit: Option<&'a ExitResources>,
+}
+
+impl AuditEntry<'_> {
+    fn to_json(&self) -> Value {
+        let number = |n: u128| Value::Number(n.to_string());
+        let timestamp = prost_types::Timestamp::from(SystemTime::now());
+
+        let mut fields = vec![
+            (
+                "timestamp".to_string(),
+                Value::String(timestamp.to_string()),
+            ),
+            ("pid".to_string(), number(self.pid.into())),
+            ("processor".to_string(), number(self.processor.into())),
+            (
+                "command".to_string(),
+                Value::Array(self.command.iter().cloned().map(Value::String).collect()),
+            ),
+            (
+                "working_directory".to_string(),
+                Value::String(self.working_directory.to_string()),
+            ),
+        ];
+
+        // `exit_code` is null unless the command exited, a killed one has the `signal` instead
+        match self.exit {
+            Some(e) => {
+                let code = match e.status.code() {
+                    Some(code) => Value::Number(code.to_string()),
+                    None => Value::Null,
+                };
+                fields.push(("exit_code".to_string(), code));
+                if let Some(signal) = e.status.signal() {
+                    fields.push(("signal".to_string(), Value::Number(signal.to_string())));
+                }
+                fields.extend([
+                    ("utime_us".to_string(), number(e.rusage.utime.as_micros())),
+                    ("stime_us".to_string(), number(e.rusage.stime.as_micros())),
+                    ("maxrss_bytes".to_string(), number(e.rusage.maxrss.into())),
+                ]);
+            }
+            None => fields.push(("exit_code".to_string(), Value::Null)),
+        }
+
+        Value::Object(fields)
+    }
+}
+
+/// Append-only JSON lines log of every job run
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    max_size: Option<u64>,
+    /// Serializes appends and rotation, lines from concurrent jobs must not interleave
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: &Path, max_size: Option<u64>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            max_size,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Appends the entry, failures are logged but never fail the job itself
+    pub async fn record(&self, entry: &AuditEntry<'_>) {
+        if let Err(e) = self.append(&format!("{}\n", entry.to_json())).await {
+            warn!("Failed to write audit log {:?}: {}", self.path, e);
+        }
+    }
+
+    async fn append(&self, line: &str) -> Result<()> {
+        let _guard = self.lock.lock().await;
+
+        if let Some(max_size) = self.max_size {
+            match tokio::fs::metadata(&self.path).await {
+                Ok(m) if m.len() > 0 && m.len() + line.len() as u64 > max_size => {
+                    let mut rotated = self.path.clone().into_os_string();
+                    rotated.push(".1");
+                    tokio::fs::rename(&self.path, rotated).await?;
+                }
+                _ => {}
+            }
+        }
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::ResourceUsage;
+    use std::process::ExitStatus;
+    use std::time::Duration;
+
+    fn exit_resources(status: ExitStatus) -> ExitResources {
+        ExitResources {
+            status,
+            rusage: ResourceUsage {
+                utime: Duration::from_micros(1500),
+                stime: Duration::ZERO,
+                maxrss: 4096,
+            },
+            cpu_stat: None,
+            memory_io_stat: None,
+            oom_kills: 0,
+            timed_out: false,
+        }
+    }
+
+    fn line(exit: Option<&ExitResources>) -> Value {
+        let entry = AuditEntry {
+            pid: 7,
+            processor: 1,
+            command: &["cc".to_string()],
+            working_directory: "src",
+            exit,
+        };
+        // What is read back from the file, not just what was built
+        crate::json::parse(&entry.to_json().to_string()).unwrap()
+    }
+
+    fn field<'a>(line: &'a Value, name: &str) -> Option<&'a Value> {
+        let fields = line.as_object().unwrap();
+        fields.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+
+    #[test]
+    fn exited_command() {
+        let exit = exit_resources(ExitStatus::from_raw(3 << 8));
+        let line = line(Some(&exit));
+        assert_eq!(field(&line, "exit_code").and_then(Value::as_u64), Some(3));
+        assert_eq!(field(&line, "signal"), None);
+        assert_eq!(field(&line, "utime_us").and_then(Value::as_u64), Some(1500));
+        assert_eq!(
+            field(&line, "maxrss_bytes").and_then(Value::as_u64),
+            Some(4096)
+        );
+    }
+
+    #[test]
+    fn killed_command_has_no_exit_code() {
+        let exit = exit_resources(ExitStatus::from_raw(nix::libc::SIGKILL));
+        let line = line(Some(&exit));
+        assert_eq!(field(&line, "exit_code"), Some(&Value::Null));
+        assert_eq!(
+            field(&line, "signal").and_then(Value::as_u64),
+            Some(nix::libc::SIGKILL as u64)
+        );
+    }
+
+    #[test]
+    fn failed_wait_has_no_exit_code() {
+        let line = line(None);
+        assert_eq!(field(&line, "exit_code"), Some(&Value::Null));
+        assert_eq!(field(&line, "signal"), None);
+        assert_eq!(field(&line, "utime_us"), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs;
+use std::io::{Error, Result};
+
+use nix::libc;
+
+/// Names and numbers from linux/capability.h, newer kernels may have more
+const CAPABILITIES: &[(&str, u32)] = &[
+    ("CAP_CHOWN", 0),
+    ("CAP_DAC_OVERRIDE", 1),
+    ("CAP_DAC_READ_SEARCH", 2),
+    ("CAP_FOWNER", 3),
+    ("CAP_FSETID", 4),
+    ("CAP_KILL", 5),
+    ("CAP_SETGID", 6),
+    ("CAP_SETUID", 7),
+    ("CAP_SETPCAP", 8),
+    ("CAP_LINUX_IMMUTABLE", 9),
+    ("CAP_NET_BIND_SERVICE", 10),
+    ("CAP_NET_BROADCAST", 11),
+    ("CAP_NET_ADMIN", 12),
+    ("CAP_NET_RAW", 13),
+    ("CAP_IPC_LOCK", 14),
+    ("CAP_IPC_OWNER", 15),
+    ("CAP_SYS_MODULE", 16),
+    ("CAP_SYS_RAWIO", 17),
+    ("CAP_SYS_CHROOT", 18),
+    ("CAP_SYS_PTRACE", 19),
+    ("CAP_SYS_PACCT", 20),
+    ("CAP_SYS_ADMIN", 21),
+    ("CAP_SYS_BOOT", 22),
+    ("CAP_SYS_NICE", 23),
+    ("CAP_SYS_RESOURCE", 24),
+    ("CAP_SYS_TIME", 25),
+    ("CAP_SYS_TTY_CONFIG", 26),
+    ("CAP_MKNOD", 27),
+    ("CAP_LEASE", 28),
+    ("CAP_AUDIT_WRITE", 29),
+    ("CAP_AUDIT_CONTROL", 30),
+    ("CAP_SETFCAP", 31),
+    ("CAP_MAC_OVERRIDE", 32),
+    ("CAP_MAC_ADMIN", 33),
+    ("CAP_SYSLOG", 34),
+    ("CAP_WAKE_ALARM", 35),
+    ("CAP_BLOCK_SUSPEND", 36),
+    ("CAP_AUDIT_READ", 37),
+    ("CAP_PERFMON", 38),
+    ("CAP_BPF", 39),
+    ("CAP_CHECKPOINT_RESTORE", 40),
+];
+
+/// Dropped unless the configuration says otherwise, what a build has no business doing even
+/// within its own namespaces
+pub(crate) const DEFAULT_DROP_LIST: &[&str] = &[
+    "CAP_SYS_ADMIN",
+    "CAP_NET_ADMIN",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_BOOT",
+];
+
+/// Kept when dropping all capabilities, the set container runtimes give by default, which build
+/// steps running as root in the sandbox expect for chown, setuid and the like
+const KEPT_BY_DROP_ALL: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_SETFCAP",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_RAW",
+    "CAP_SYS_CHROOT",
+    "CAP_MKNOD",
+    "CAP_AUDIT_WRITE",
+];
+
+/// Number of the capability `name`, e.g. `CAP_SYS_ADMIN`
+pub(crate) fn capability_number(name: &str) -> Option<u32> {
+    CAPABILITIES
+        .iter()
+        .find(|&&(n, _)| n == name)
+        .map(|&(_, cap)| cap)
+}
+
+/// Every capability the kernel has except [`KEPT_BY_DROP_ALL`]
+pub(crate) fn all_but_minimal() -> Vec<u32> {
+    let last_cap = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or_else(|| CAPABILITIES.iter().map(|&(_, cap)| cap).max().unwrap_or(0));
+    let kept: Vec<u32> = KEPT_BY_DROP_ALL
+        .iter()
+        .filter_map(|name| capability_number(name))
+        .collect();
+
+    (0..=last_cap).filter(|cap| !kept.contains(cap)).collect()
+}
+
+/// Removes `caps` from the bounding set, so no execve can give them to the command again. The
+/// caller's own effective capabilities are unchanged.
+///
+/// A capability the kernel doesn't know is skipped, there is nothing to drop.
+pub(crate) fn drop_bounding_set(caps: &[u32]) -> Result<()> {
+    for &cap in caps {
+        let r = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, libc::c_ulong::from(cap), 0, 0, 0) };
+        if r < 0 {
+            let e = Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EINVAL) {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1112 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::unistd::Pid;
+use tokio::io::unix::AsyncFd;
+use tracing::{debug, info, warn};
+
+use crate::config::{CgroupCleanupPolicy, CgroupConfig, SwapPolicy};
+use crate::error::{RunnerError, RunnerResult};
+use crate::health::Health;
+use crate::resource::ExitReason;
+
+/// Mount point of the cgroup filesystem, v1 has one hierarchy per controller below this
+const CGROUP_FS: &str = "/sys/fs/cgroup";
+
+/// Parent cgroup of all the job cgroups, relative to the hierarchy root
+const CGROUP_NAME: &str = "bb_runner";
+
+/// v1 hierarchy the cpu and cpuacct controllers are usually co-mounted in
+const CPUACCT_CONTROLLER: &str = "cpu,cpuacct";
+
+const JOB_MEMORY_MAX: u64 = 1024 * 1024 * 1024;
+
+const PSI_INTERVAL: Duration = Duration::from_secs(10);
+
+const CONTROLLER_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CgroupVersion {
+    V1,
+    V2,
+}
+
+/// The unified hierarchy has `cgroup.controllers` at its root, v1 does not
+pub(crate) fn detect_cgroup_version() -> CgroupVersion {
+    if Path::new(CGROUP_FS).join("cgroup.controllers").exists() {
+        CgroupVersion::V2
+    } else {
+        CgroupVersion::V1
+    }
+}
+
+/// Where the runner's cgroups are, below the cgroup filesystem `root`
+pub(crate) struct CgroupPaths;
+
+/// A job's cgroup in each of the v1 hierarchies it is put in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CgroupV1Paths {
+    pub memory: PathBuf,
+    /// In the co-mounted `cpu,cpuacct` hierarchy
+    pub cpu: PathBuf,
+    pub cpuset: PathBuf,
+}
+
+impl CgroupPaths {
+    /// The runner's own cgroup in the unified hierarchy, the parent of every job
+    pub fn runner_v2(root: &Path) -> PathBuf {
+        root.join(CGROUP_NAME)
+    }
+
+    pub fn for_v2(root: &Path, job: &str) -> PathBuf {
+        Self::runner_v2(root).join(format!("job{job}"))
+    }
+
+    /// The runner's own cgroup in one v1 hierarchy
+    pub fn runner_v1(root: &Path, controller: &str) -> PathBuf {
+        root.join(controller).join(CGROUP_NAME)
+    }
+
+    pub fn for_v1(root: &Path, job: &str) -> CgroupV1Paths {
+        Self::v1_named(root, format!("job{job}").as_ref())
+    }
+
+    /// The v1 cgroups of the job whose memory cgroup is `memory_dir`
+    pub fn v1_of_memory_dir(root: &Path, memory_dir: &Path) -> Option<CgroupV1Paths> {
+        Some(Self::v1_named(root, memory_dir.file_name()?))
+    }
+
+    fn v1_named(root: &Path, name: &OsStr) -> CgroupV1Paths {
+        CgroupV1Paths {
+            memory: Self::runner_v1(root, "memory").join(name),
+            cpu: Self::runner_v1(root, CPUACCT_CONTROLLER).join(name),
+            cpuset: Self::runner_v1(root, "cpuset").join(name),
+        }
+    }
+}
+
+/// Filesystem operations needed to configure a cgroup.
+///
+/// Cgroups are configured purely by writing to files under `/sys/fs/cgroup`, routing those
+/// writes through a trait keeps the cgroup logic independent of the real cgroupfs.
+pub(crate) trait CgroupWriter {
+    /// Replace the contents of a cgroup control file
+    fn write(&self, path: &Path, content: &str) -> Result<()>;
+
+    /// Read a cgroup control file
+    fn read(&self, path: &Path) -> Result<String>;
+
+    /// Create a new cgroup directory, failing with `AlreadyExists` if it is there already
+    fn create_dir(&self, path: &Path) -> Result<()>;
+
+    /// Remove an empty cgroup directory
+    fn remove_dir(&self, path: &Path) -> Result<()>;
+
+    /// Rename a cgroup directory within its parent, the processes in it stay put
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Fails unless the control file could be written, without writing anything to it
+    fn check_writable(&self, path: &Path) -> Result<()>;
+}
+
+/// Writes directly to the cgroup filesystem
+#[derive(Debug, Default)]
+pub(crate) struct RealCgroupWriter;
+
+impl CgroupWriter for RealCgroupWriter {
+    fn write(&self, path: &Path, content: &str) -> Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+    }
+
+    fn read(&self, path: &Path) -> Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        std::fs::create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        std::fs::remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn check_writable(&self, path: &Path) -> Result<()> {
+        Ok(nix::unistd::access(path, nix::unistd::AccessFlags::W_OK)?)
+    }
+}
+
+fn cg_write(writer: &dyn CgroupWriter, path: &Path, content: &str) -> RunnerResult<()> {
+    writer
+        .write(path, content)
+        .map_err(|source| RunnerError::Cgroup {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+fn cg_read(writer: &dyn CgroupWriter, path: &Path) -> RunnerResult<String> {
+    writer.read(path).map_err(|source| RunnerError::Cgroup {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Creates the job's cgroup at `path`, always a fresh one. A cgroup already there was kept by a
+/// job that couldn't be set aside, or by a runner that didn't get to clean up, its counters must
+/// not be mixed into the new job's.
+fn cg_create_dir(writer: &dyn CgroupWriter, path: &Path) -> RunnerResult<()> {
+    let map_err = |source| RunnerError::Cgroup {
+        path: path.to_path_buf(),
+        source,
+    };
+    match writer.create_dir(path) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            if writer.remove_dir(path).is_err() {
+                let aside = set_aside_path(path);
+                warn!("Moving stale cgroup {:?} to {:?}", path, aside);
+                writer.rename(path, &aside).map_err(map_err)?;
+            }
+            writer.create_dir(path).map_err(map_err)
+        }
+        res => res.map_err(map_err),
+    }
+}
+
+/// Where a kept cgroup is moved to, `job0` becomes `job0.1760400000123` so that the slot's
+/// name is free for the next job
+fn set_aside_path(path: &Path) -> PathBuf {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{millis}"));
+    path.with_file_name(name)
+}
+
+/// Moves the child into the cgroup of job slot `job`, pinned to `cpus`, and applies the limits
+///
+/// Returns the job's cgroup directory, on cgroup v1 that is the one in the memory hierarchy.
+pub(crate) fn move_child_cgroup(
+    writer: &dyn CgroupWriter,
+    pid: Pid,
+    job: &str,
+    cpus: &str,
+    config: &CgroupConfig,
+) -> RunnerResult<PathBuf> {
+    match detect_cgroup_version() {
+        CgroupVersion::V2 => move_child_cgroup_v2(writer, pid, job, cpus, config),
+        CgroupVersion::V1 => move_child_cgroup_v1(writer, pid, job, cpus, config),
+    }
+}
+
+/// [`move_child_cgroup`] on the blocking thread pool
+///
+/// cgroupfs writes can stall while the kernel migrates a busy cgroup, and the runtime only has
+/// the one thread that serves every RPC.
+pub(crate) async fn setup_cgroup(
+    pid: Pid,
+    job: String,
+    cpus: String,
+    config: CgroupConfig,
+) -> RunnerResult<PathBuf> {
+    tokio::task::spawn_blocking(move || {
+        move_child_cgroup(&RealCgroupWriter, pid, &job, &cpus, &config)
+    })
+    .await
+    .map_err(|e| RunnerError::Cgroup {
+        path: PathBuf::from(CGROUP_FS),
+        source: Error::other(e),
+    })?
+}
+
+#[tracing::instrument(ret, skip(writer))]
+fn move_child_cgroup_v2(
+    writer: &dyn CgroupWriter,
+    pid: Pid,
+    job: &str,
+    cpus: &str,
+    config: &CgroupConfig,
+) -> RunnerResult<PathBuf> {
+    let cgroup_dir = CgroupPaths::for_v2(Path::new(CGROUP_FS), job);
+    cg_create_dir(writer, &cgroup_dir)?;
+
+    cg_write(writer, &cgroup_dir.join("cgroup.procs"), &format!("{pid}"))?;
+    cg_write(writer, &cgroup_dir.join("cpuset.cpus"), cpus)?;
+
+    let swap_max = match config.swap_policy {
+        SwapPolicy::Disabled => Some(0),
+        SwapPolicy::Unlimited => None,
+        SwapPolicy::MaxBytes(n) => Some(n),
+        SwapPolicy::SameAsMemory => Some(JOB_MEMORY_MAX),
+    };
+    if let Some(n) = swap_max {
+        cg_write(writer, &cgroup_dir.join("memory.swap.max"), &n.to_string())?;
+    }
+    cg_write(
+        writer,
+        &cgroup_dir.join("memory.max"),
+        &JOB_MEMORY_MAX.to_string(),
+    )?;
+    let oom_group = if config.oom_group { "1" } else { "0" };
+    cg_write(writer, &cgroup_dir.join("memory.oom.group"), oom_group)?;
+
+    Ok(cgroup_dir)
+}
+
+/// Every v1 controller is a separate hierarchy, so the job gets a cgroup in each of them.
+///
+/// v1 has no separate swap limit, `memory.memsw.limit_in_bytes` limits memory plus swap
+/// together and must be set after `memory.limit_in_bytes`.
+#[tracing::instrument(ret, skip(writer))]
+fn move_child_cgroup_v1(
+    writer: &dyn CgroupWriter,
+    pid: Pid,
+    job: &str,
+    cpus: &str,
+    config: &CgroupConfig,
+) -> RunnerResult<PathBuf> {
+    let root = Path::new(CGROUP_FS);
+    let paths = CgroupPaths::for_v1(root, job);
+
+    // cpuset refuses tasks until both cpus and mems are set, use the same mems as the parent
+    cg_create_dir(writer, &paths.cpuset)?;
+    cg_write(writer, &paths.cpuset.join("cpuset.cpus"), cpus)?;
+    let parent_mems = CgroupPaths::runner_v1(root, "cpuset").join("cpuset.mems");
+    let mems = cg_read(writer, &parent_mems)?;
+    cg_write(writer, &paths.cpuset.join("cpuset.mems"), mems.trim())?;
+
+    let memory_dir = &paths.memory;
+    cg_create_dir(writer, memory_dir)?;
+    cg_write(
+        writer,
+        &memory_dir.join("memory.limit_in_bytes"),
+        &JOB_MEMORY_MAX.to_string(),
+    )?;
+
+    let memsw_limit = match config.swap_policy {
+        SwapPolicy::Disabled => {
+            cg_write(writer, &memory_dir.join("memory.swappiness"), "0")?;
+            Some(JOB_MEMORY_MAX)
+        }
+        SwapPolicy::Unlimited => None,
+        SwapPolicy::MaxBytes(n) => Some(JOB_MEMORY_MAX.saturating_add(n)),
+        SwapPolicy::SameAsMemory => Some(JOB_MEMORY_MAX.saturating_mul(2)),
+    };
+    if let Some(n) = memsw_limit {
+        cg_write(
+            writer,
+            &memory_dir.join("memory.memsw.limit_in_bytes"),
+            &n.to_string(),
+        )?;
+    }
+
+    // Only for accounting, cpuacct.usage counts processes that pid1 never got to reap
+    cg_create_dir(writer, &paths.cpu)?;
+
+    let cgproc = format!("{pid}");
+    for dir in [&paths.cpuset, &paths.memory, &paths.cpu] {
+        cg_write(writer, &dir.join("cgroup.procs"), &cgproc)?;
+    }
+
+    Ok(paths.memory)
+}
+
+/// CPU accounting from the cgroup v2 `cpu.stat` file
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CpuStat {
+    pub usage_usec: u64,
+    pub user_usec: u64,
+    pub system_usec: u64,
+    /// Periods in which the cgroup hit its `cpu.max` quota
+    pub nr_throttled: u64,
+    pub throttled_usec: u64,
+}
+
+/// `cpu.stat` is one `key value` pair per line, keys we don't know about are skipped
+pub(crate) fn read_cpu_stat(cgroup_dir: &Path) -> Result<CpuStat> {
+    let contents = std::fs::read_to_string(cgroup_dir.join("cpu.stat"))?;
+
+    let mut stat = CpuStat::default();
+    for line in contents.lines() {
+        let Some((key, val)) = line.split_once(' ') else {
+            continue;
+        };
+        let val: u64 = val
+            .trim()
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("bad cpu.stat: {line}")))?;
+        match key {
+            "usage_usec" => stat.usage_usec = val,
+            "user_usec" => stat.user_usec = val,
+            "system_usec" => stat.system_usec = val,
+            "nr_throttled" => stat.nr_throttled = val,
+            "throttled_usec" => stat.throttled_usec = val,
+            _ => {}
+        }
+    }
+
+    Ok(stat)
+}
+
+/// Memory and IO accounting of a cgroup v2
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct MemoryIoStat {
+    /// Zero if the kernel has no `memory.peak`, it appeared in 5.19
+    pub memory_peak_bytes: u64,
+    /// Zero unless the io controller is enabled for the job's cgroup
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+}
+
+/// Contents of a cgroup file that may not exist, depending on the kernel and controllers
+fn read_optional(path: &Path) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        res => res,
+    }
+}
+
+/// Reads `memory.peak` and `io.stat`, the latter has one line of `key=value` pairs per device
+/// and those are summed up
+pub(crate) fn read_memory_io_stat(cgroup_dir: &Path) -> Result<MemoryIoStat> {
+    let mut stat = MemoryIoStat::default();
+    let peak = read_optional(&cgroup_dir.join("memory.peak"))?;
+    if !peak.is_empty() {
+        stat.memory_peak_bytes = peak
+            .trim()
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("bad memory.peak: {peak}")))?;
+    }
+
+    let contents = read_optional(&cgroup_dir.join("io.stat"))?;
+    for pair in contents.lines().flat_map(|l| l.split_whitespace().skip(1)) {
+        let Some((key, val)) = pair.split_once('=') else {
+            continue;
+        };
+        let val: u64 = val
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("bad io.stat: {pair}")))?;
+        match key {
+            "rbytes" => stat.io_read_bytes += val,
+            "wbytes" => stat.io_write_bytes += val,
+            _ => {}
+        }
+    }
+
+    Ok(stat)
+}
+
+/// Memory the job's cgroup uses right now: `memory.current` on v2, `memory.usage_in_bytes` in
+/// the v1 memory hierarchy
+pub(crate) fn read_memory_current(
+    writer: &dyn CgroupWriter,
+    version: CgroupVersion,
+    cgroup_dir: &Path,
+) -> Result<u64> {
+    let file = match version {
+        CgroupVersion::V2 => "memory.current",
+        CgroupVersion::V1 => "memory.usage_in_bytes",
+    };
+    let contents = writer.read(&cgroup_dir.join(file))?;
+    contents
+        .trim()
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("bad {file}: {contents}")))
+}
+
+/// The v1 cpuacct cgroup of the job whose memory cgroup is `job_dir`
+pub(crate) fn cpuacct_dir_v1(job_dir: &Path) -> Option<PathBuf> {
+    CgroupPaths::v1_of_memory_dir(Path::new(CGROUP_FS), job_dir).map(|p| p.cpu)
+}
+
+/// Number of processes in the job killed by the OOM killer
+///
+/// `job_dir` is the memory cgroup, v2 counts them in `memory.events` and v1 in
+/// `memory.oom_control` with the same `oom_kill N` line.
+pub(crate) fn read_oom_kills(job_dir: &Path) -> Option<u64> {
+    let file = match detect_cgroup_version() {
+        CgroupVersion::V2 => "memory.events",
+        CgroupVersion::V1 => "memory.oom_control",
+    };
+    let contents = std::fs::read_to_string(job_dir.join(file)).ok()?;
+
+    contents
+        .lines()
+        .find_map(|l| l.strip_prefix("oom_kill "))
+        .and_then(|n| n.trim().parse().ok())
+}
+
+/// Wakes up whenever the v2 `memory.events` of a job changes, the kernel notifies a modification
+/// of it for every counter that goes up, `oom_kill` included.
+///
+/// v1 only offers an eventfd on `memory.oom_control`, which isn't supported.
+pub(crate) struct MemoryEventsWatch(AsyncFd<InotifyFd>);
+
+struct InotifyFd(Inotify);
+
+impl AsRawFd for InotifyFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_fd().as_raw_fd()
+    }
+}
+
+impl MemoryEventsWatch {
+    pub fn new(job_dir: &Path) -> Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(&job_dir.join("memory.events"), AddWatchFlags::IN_MODIFY)?;
+        Ok(Self(AsyncFd::new(InotifyFd(inotify))?))
+    }
+
+    /// Returns after the next change, the events themselves carry no information
+    pub async fn changed(&self) -> Result<()> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            match guard.try_io(|fd| fd.get_ref().0.read_events().map_err(Error::from)) {
+                Ok(res) => return res.map(|_| ()),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Removes the job's cgroups after it exited, unless `policy` says to keep them
+///
+/// Kept cgroups are renamed aside with a timestamp suffix, the next job on the same slot starts
+/// in a fresh cgroup.
+pub(crate) fn cleanup_job_cgroup(
+    writer: &dyn CgroupWriter,
+    job_dir: &Path,
+    policy: CgroupCleanupPolicy,
+    reason: ExitReason,
+) {
+    cleanup_job_cgroup_of(writer, detect_cgroup_version(), job_dir, policy, reason)
+}
+
+fn cleanup_job_cgroup_of(
+    writer: &dyn CgroupWriter,
+    version: CgroupVersion,
+    job_dir: &Path,
+    policy: CgroupCleanupPolicy,
+    reason: ExitReason,
+) {
+    let remove = match policy {
+        CgroupCleanupPolicy::Always => true,
+        CgroupCleanupPolicy::OnSuccess => reason == ExitReason::Success,
+        CgroupCleanupPolicy::OnFailure => reason != ExitReason::Success,
+        CgroupCleanupPolicy::Never => false,
+    };
+
+    let dirs = match version {
+        CgroupVersion::V2 => vec![job_dir.to_path_buf()],
+        CgroupVersion::V1 => match CgroupPaths::v1_of_memory_dir(Path::new(CGROUP_FS), job_dir) {
+            Some(paths) => vec![paths.cpuset, paths.cpu, paths.memory],
+            None => vec![job_dir.to_path_buf()],
+        },
+    };
+    for dir in &dirs {
+        if remove {
+            if let Err(e) = writer.remove_dir(dir) {
+                warn!("Failed to remove cgroup {:?}: {}", dir, e);
+            }
+            continue;
+        }
+        let aside = set_aside_path(dir);
+        match writer.rename(dir, &aside) {
+            Ok(()) => warn!(
+                "cgroup left at {:?} for inspection, exit reason {:?}",
+                aside, reason
+            ),
+            Err(e) => warn!("Failed to set aside cgroup {:?}: {}", dir, e),
+        }
+    }
+}
+
+/// Total CPU time of every process that was ever in the v1 cgroup
+///
+/// `cpuacct.usage` does not split user and system time. `cpuacct.stat` does, but only in
+/// USER_HZ ticks, so the split from rusage is kept and the total is taken from here.
+pub(crate) fn read_cpuacct_usage(cpu_cgroup_dir: &Path) -> Option<Duration> {
+    let contents = std::fs::read_to_string(cpu_cgroup_dir.join("cpuacct.usage")).ok()?;
+    contents.trim().parse().ok().map(Duration::from_nanos)
+}
+
+/// Freezes or thaws every process in the cgroup, only cgroup v2 has `cgroup.freeze`
+pub(crate) fn set_frozen(
+    writer: &dyn CgroupWriter,
+    cgroup_dir: &Path,
+    frozen: bool,
+) -> RunnerResult<()> {
+    let path = cgroup_dir.join("cgroup.freeze");
+    if detect_cgroup_version() != CgroupVersion::V2 {
+        return Err(RunnerError::Cgroup {
+            path,
+            source: Error::new(ErrorKind::Unsupported, "freezing jobs requires cgroup v2"),
+        });
+    }
+
+    cg_write(writer, &path, if frozen { "1" } else { "0" })
+}
+
+/// One line of a PSI file such as `cpu.pressure`
+///
+/// `some avg10=0.00 avg60=0.00 avg300=0.00 total=0`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Pressure {
+    /// Percentage of time stalled, averaged over 10, 60 and 300 seconds
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    /// Total stall time in microseconds
+    pub total: u64,
+}
+
+/// Parse the line starting with `kind` ("some" or "full") from a PSI file
+fn parse_pressure(contents: &str, kind: &str) -> Option<Pressure> {
+    let line = contents.lines().find(|l| l.starts_with(kind))?;
+
+    let mut pressure = Pressure::default();
+    for field in line.split_whitespace().skip(1) {
+        let (name, val) = field.split_once('=')?;
+        match name {
+            "avg10" => pressure.avg10 = val.parse().ok()?,
+            "avg60" => pressure.avg60 = val.parse().ok()?,
+            "avg300" => pressure.avg300 = val.parse().ok()?,
+            "total" => pressure.total = val.parse().ok()?,
+            _ => {}
+        }
+    }
+
+    Some(pressure)
+}
+
+/// Why `resource` counts as under pressure, if its avg10 is above `threshold` percent
+fn pressure_over(resource: &str, pressure: &Pressure, threshold: f64) -> Option<String> {
+    (pressure.avg10 > threshold).then(|| format!("{resource} avg10={:.2}", pressure.avg10))
+}
+
+/// Periodically reads the pressure of the bb_runner cgroup, marking the runner as not ready
+/// while any resource has been stalled for more than `threshold` percent recently.
+///
+/// New jobs dispatched to a machine under heavy memory pressure are likely to get OOM killed,
+/// better to let the scheduler send them elsewhere. Only changes are logged, a file that can't be
+/// read or a pressure that stays high is reported once, not on every tick.
+pub(crate) async fn monitor_pressure(health: Health, threshold: f64) {
+    let mut interval = tokio::time::interval(PSI_INTERVAL);
+    let mut unreadable = HashSet::new();
+
+    loop {
+        interval.tick().await;
+
+        let mut over = Vec::new();
+        for resource in ["cpu", "memory", "io"] {
+            let path =
+                CgroupPaths::runner_v2(Path::new(CGROUP_FS)).join(format!("{resource}.pressure"));
+            let pressure = match tokio::fs::read_to_string(&path).await {
+                Ok(contents) => parse_pressure(&contents, "some")
+                    .ok_or_else(|| format!("Failed to parse {path:?}")),
+                Err(e) => Err(format!("Failed to read {path:?}: {e}")),
+            };
+            let p = match pressure {
+                Ok(p) => p,
+                Err(e) => {
+                    if unreadable.insert(resource) {
+                        warn!("{}", e);
+                    }
+                    continue;
+                }
+            };
+            if unreadable.remove(resource) {
+                info!("{:?} is readable again", path);
+            }
+            debug!(
+                resource,
+                avg10 = p.avg10,
+                avg60 = p.avg60,
+                avg300 = p.avg300,
+                total = p.total,
+                "pressure"
+            );
+
+            over.extend(pressure_over(resource, &p, threshold));
+        }
+
+        if over.is_empty() {
+            if health.set_healthy("psi") {
+                info!("pressure back below {}", threshold);
+            }
+        } else {
+            let reason = format!("pressure too high: {}", over.join(", "));
+            if health.set_unhealthy("psi", reason.clone()) {
+                warn!("{}", reason);
+            } else {
+                debug!("{}", reason);
+            }
+        }
+    }
+}
+
+/// Marks the runner not ready while the cgroups jobs are put in are unusable
+///
+/// Controllers can go away under a running runner, e.g. when systemd rewrites
+/// `cgroup.subtree_control`, and then every job would fail to start. This only checks, writing a
+/// pid into `cgroup.procs` to test it would move that process. Only going unusable and
+/// recovering are logged.
+pub(crate) async fn monitor_controllers(health: Health) {
+    let mut interval = tokio::time::interval(CONTROLLER_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let check = || {
+            let root = Path::new(CGROUP_FS);
+            check_controllers(&RealCgroupWriter, root, detect_cgroup_version())
+        };
+        match tokio::task::spawn_blocking(check).await {
+            Ok(Ok(())) => {
+                if health.set_healthy("cgroup") {
+                    info!("cgroup usable again");
+                }
+            }
+            Ok(Err(reason)) => {
+                if health.set_unhealthy("cgroup", reason.clone()) {
+                    warn!("cgroup unusable: {}", reason);
+                } else {
+                    debug!("cgroup still unusable: {}", reason);
+                }
+            }
+            Err(e) => warn!("cgroup check failed: {}", e),
+        }
+    }
+}
+
+/// The parent cgroups of the jobs below `root` must be writable and, on v2, hand the controllers
+/// the job limits need down to the job cgroups
+fn check_controllers(
+    writer: &dyn CgroupWriter,
+    root: &Path,
+    version: CgroupVersion,
+) -> std::result::Result<(), String> {
+    let dirs = match version {
+        CgroupVersion::V2 => {
+            let dir = CgroupPaths::runner_v2(root);
+            let path = dir.join("cgroup.subtree_control");
+            let enabled = writer.read(&path).map_err(|e| format!("{path:?}: {e}"))?;
+            let missing: Vec<&str> = ["cpuset", "memory"]
+                .into_iter()
+                .filter(|c| !enabled.split_whitespace().any(|e| e == *c))
+                .collect();
+            if !missing.is_empty() {
+                return Err(format!("{path:?} lacks {}", missing.join(", ")));
+            }
+            vec![dir]
+        }
+        CgroupVersion::V1 => ["cpuset", "memory", CPUACCT_CONTROLLER]
+            .into_iter()
+            .map(|controller| CgroupPaths::runner_v1(root, controller))
+            .collect(),
+    };
+
+    for dir in dirs {
+        let procs = dir.join("cgroup.procs");
+        writer
+            .check_writable(&procs)
+            .map_err(|e| format!("{procs:?} is not writable: {e}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    enum Op {
+        Write(PathBuf, String),
+        CreateDir(PathBuf),
+        RemoveDir(PathBuf),
+        Rename(PathBuf, PathBuf),
+    }
+
+    /// Records every operation, reads are answered from `files`
+    #[derive(Default)]
+    struct MockCgroupWriter {
+        ops: RefCell<Vec<Op>>,
+        files: HashMap<PathBuf, String>,
+        /// Directories that exist and are not empty
+        busy_dirs: RefCell<Vec<PathBuf>>,
+        /// Control files the runner may not write
+        readonly: Vec<PathBuf>,
+    }
+
+    impl MockCgroupWriter {
+        fn ops(&self) -> Vec<Op> {
+            self.ops.take()
+        }
+    }
+
+    impl CgroupWriter for MockCgroupWriter {
+        fn write(&self, path: &Path, content: &str) -> Result<()> {
+            let op = Op::Write(path.to_path_buf(), content.to_string());
+            self.ops.borrow_mut().push(op);
+            Ok(())
+        }
+
+        fn read(&self, path: &Path) -> Result<String> {
+            self.files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| Error::from(ErrorKind::NotFound))
+        }
+
+        fn create_dir(&self, path: &Path) -> Result<()> {
+            self.ops
+                .borrow_mut()
+                .push(Op::CreateDir(path.to_path_buf()));
+            if self.busy_dirs.borrow().iter().any(|d| d == path) {
+                return Err(Error::from(ErrorKind::AlreadyExists));
+            }
+            Ok(())
+        }
+
+        fn remove_dir(&self, path: &Path) -> Result<()> {
+            self.ops
+                .borrow_mut()
+                .push(Op::RemoveDir(path.to_path_buf()));
+            if self.busy_dirs.borrow().iter().any(|d| d == path) {
+                return Err(Error::from_raw_os_error(nix::libc::EBUSY));
+            }
+            Ok(())
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+            let op = Op::Rename(from.to_path_buf(), to.to_path_buf());
+            self.ops.borrow_mut().push(op);
+            self.busy_dirs.borrow_mut().retain(|d| d != from);
+            Ok(())
+        }
+
+        fn check_writable(&self, path: &Path) -> Result<()> {
+            if self.readonly.iter().any(|p| p == path) {
+                return Err(Error::from(ErrorKind::PermissionDenied));
+            }
+            Ok(())
+        }
+    }
+
+    fn write(path: &str, content: &str) -> Op {
+        Op::Write(PathBuf::from(path), content.to_string())
+    }
+
+    fn create_dir(path: &str) -> Op {
+        Op::CreateDir(PathBuf::from(path))
+    }
+
+    fn remove_dir(path: &str) -> Op {
+        Op::RemoveDir(PathBuf::from(path))
+    }
+
+    /// The renamed path of a kept cgroup, whatever its timestamp
+    fn renamed_from(op: &Op) -> Option<&Path> {
+        match op {
+            Op::Rename(from, to) => {
+                let suffix = to.to_str()?.strip_prefix(from.to_str()?)?;
+                let millis = suffix.strip_prefix('.')?;
+                millis.parse::<u128>().ok().map(|_| from.as_path())
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn move_child_cgroup_v2_writes_limits() {
+        let writer = MockCgroupWriter::default();
+        let config = CgroupConfig::default();
+        let dir = move_child_cgroup_v2(&writer, Pid::from_raw(42), "3", "2-3", &config).unwrap();
+
+        assert_eq!(dir, PathBuf::from("/sys/fs/cgroup/bb_runner/job3"));
+        assert_eq!(
+            writer.ops(),
+            vec![
+                create_dir("/sys/fs/cgroup/bb_runner/job3"),
+                write("/sys/fs/cgroup/bb_runner/job3/cgroup.procs", "42"),
+                write("/sys/fs/cgroup/bb_runner/job3/cpuset.cpus", "2-3"),
+                write("/sys/fs/cgroup/bb_runner/job3/memory.swap.max", "0"),
+                write("/sys/fs/cgroup/bb_runner/job3/memory.max", "1073741824"),
+                write("/sys/fs/cgroup/bb_runner/job3/memory.oom.group", "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn move_child_cgroup_v2_swap_and_oom_group() {
+        let writer = MockCgroupWriter::default();
+        let config = CgroupConfig {
+            swap_policy: SwapPolicy::Unlimited,
+            oom_group: false,
+            ..CgroupConfig::default()
+        };
+        move_child_cgroup_v2(&writer, Pid::from_raw(42), "0", "0", &config).unwrap();
+        let ops = writer.ops();
+        assert!(!ops
+            .iter()
+            .any(|op| matches!(op, Op::Write(p, _) if p.ends_with("memory.swap.max"))));
+        assert!(ops.contains(&write(
+            "/sys/fs/cgroup/bb_runner/job0/memory.oom.group",
+            "0"
+        )));
+
+        let config = CgroupConfig {
+            swap_policy: SwapPolicy::MaxBytes(4096),
+            ..CgroupConfig::default()
+        };
+        move_child_cgroup_v2(&writer, Pid::from_raw(42), "0", "0", &config).unwrap();
+        assert!(writer.ops().contains(&write(
+            "/sys/fs/cgroup/bb_runner/job0/memory.swap.max",
+            "4096"
+        )));
+    }
+
+    #[test]
+    fn move_child_cgroup_v1_writes_limits() {
+        let writer = MockCgroupWriter {
+            files: HashMap::from([(
+                PathBuf::from("/sys/fs/cgroup/cpuset/bb_runner/cpuset.mems"),
+                "0-1\n".to_string(),
+            )]),
+            ..MockCgroupWriter::default()
+        };
+        let config = CgroupConfig::default();
+        let dir = move_child_cgroup_v1(&writer, Pid::from_raw(42), "1", "4", &config).unwrap();
+
+        assert_eq!(dir, PathBuf::from("/sys/fs/cgroup/memory/bb_runner/job1"));
+        assert_eq!(
+            writer.ops(),
+            vec![
+                create_dir("/sys/fs/cgroup/cpuset/bb_runner/job1"),
+                write("/sys/fs/cgroup/cpuset/bb_runner/job1/cpuset.cpus", "4"),
+                write("/sys/fs/cgroup/cpuset/bb_runner/job1/cpuset.mems", "0-1"),
+                create_dir("/sys/fs/cgroup/memory/bb_runner/job1"),
+                write(
+                    "/sys/fs/cgroup/memory/bb_runner/job1/memory.limit_in_bytes",
+                    "1073741824"
+                ),
+                write(
+                    "/sys/fs/cgroup/memory/bb_runner/job1/memory.swappiness",
+                    "0"
+                ),
+                write(
+                    "/sys/fs/cgroup/memory/bb_runner/job1/memory.memsw.limit_in_bytes",
+                    "1073741824"
+                ),
+                create_dir("/sys/fs/cgroup/cpu,cpuacct/bb_runner/job1"),
+                write("/sys/fs/cgroup/cpuset/bb_runner/job1/cgroup.procs", "42"),
+                write("/sys/fs/cgroup/memory/bb_runner/job1/cgroup.procs", "42"),
+                write(
+                    "/sys/fs/cgroup/cpu,cpuacct/bb_runner/job1/cgroup.procs",
+                    "42"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn move_child_cgroup_sets_aside_a_stale_cgroup() {
+        let stale = PathBuf::from("/sys/fs/cgroup/bb_runner/job2");
+        let writer = MockCgroupWriter {
+            busy_dirs: RefCell::new(vec![stale.clone()]),
+            ..MockCgroupWriter::default()
+        };
+        let config = CgroupConfig::default();
+        move_child_cgroup_v2(&writer, Pid::from_raw(42), "2", "0", &config).unwrap();
+
+        let ops = writer.ops();
+        assert_eq!(ops[0], Op::CreateDir(stale.clone()));
+        assert_eq!(ops[1], Op::RemoveDir(stale.clone()));
+        assert_eq!(renamed_from(&ops[2]), Some(stale.as_path()));
+        assert_eq!(ops[3], Op::CreateDir(stale.clone()));
+        assert_eq!(
+            ops[4],
+            write("/sys/fs/cgroup/bb_runner/job2/cgroup.procs", "42")
+        );
+    }
+
+    #[test]
+    fn cleanup_removes_v2_cgroup() {
+        let writer = MockCgroupWriter::default();
+        let job_dir = Path::new("/sys/fs/cgroup/bb_runner/job0");
+        cleanup_job_cgroup_of(
+            &writer,
+            CgroupVersion::V2,
+            job_dir,
+            CgroupCleanupPolicy::Always,
+            ExitReason::Failure,
+        );
+        assert_eq!(
+            writer.ops(),
+            vec![remove_dir("/sys/fs/cgroup/bb_runner/job0")]
+        );
+    }
+
+    #[test]
+    fn cleanup_removes_every_v1_cgroup() {
+        let writer = MockCgroupWriter::default();
+        let job_dir = Path::new("/sys/fs/cgroup/memory/bb_runner/job0");
+        cleanup_job_cgroup_of(
+            &writer,
+            CgroupVersion::V1,
+            job_dir,
+            CgroupCleanupPolicy::OnSuccess,
+            ExitReason::Success,
+        );
+        assert_eq!(
+            writer.ops(),
+            vec![
+                remove_dir("/sys/fs/cgroup/cpuset/bb_runner/job0"),
+                remove_dir("/sys/fs/cgroup/cpu,cpuacct/bb_runner/job0"),
+                remove_dir("/sys/fs/cgroup/memory/bb_runner/job0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn cleanup_sets_aside_kept_cgroups() {
+        let job_dir = Path::new("/sys/fs/cgroup/bb_runner/job0");
+        for (policy, reason) in [
+            (CgroupCleanupPolicy::Never, ExitReason::Success),
+            (CgroupCleanupPolicy::OnSuccess, ExitReason::OomKilled),
+            (CgroupCleanupPolicy::OnFailure, ExitReason::Success),
+        ] {
+            let writer = MockCgroupWriter::default();
+            cleanup_job_cgroup_of(&writer, CgroupVersion::V2, job_dir, policy, reason);
+            let ops = writer.ops();
+            assert_eq!(ops.len(), 1, "{policy:?} {reason:?}: {ops:?}");
+            assert_eq!(renamed_from(&ops[0]), Some(job_dir));
+        }
+    }
+
+    const CPU_PRESSURE: &str = "some avg10=12.50 avg60=3.01 avg300=0.75 total=123456789\n\
+                                full avg10=1.00 avg60=0.20 avg300=0.05 total=4567\n";
+
+    #[test]
+    fn parse_pressure_lines() {
+        let some = Pressure {
+            avg10: 12.5,
+            avg60: 3.01,
+            avg300: 0.75,
+            total: 123456789,
+        };
+        assert_eq!(parse_pressure(CPU_PRESSURE, "some"), Some(some));
+        let full = parse_pressure(CPU_PRESSURE, "full").unwrap();
+        assert_eq!((full.avg10, full.total), (1.0, 4567));
+
+        // The root cgroup's cpu.pressure has no "full" line on older kernels
+        assert_eq!(
+            parse_pressure(CPU_PRESSURE.lines().next().unwrap(), "full"),
+            None
+        );
+        assert_eq!(parse_pressure("", "some"), None);
+        assert_eq!(parse_pressure("some avg10=high", "some"), None);
+        assert_eq!(parse_pressure("some avg10", "some"), None);
+        // Fields added by later kernels are skipped
+        let p = parse_pressure("some avg10=1.00 future=7 total=2", "some").unwrap();
+        assert_eq!((p.avg10, p.total), (1.0, 2));
+    }
+
+    #[test]
+    fn pressure_threshold() {
+        let p = parse_pressure(CPU_PRESSURE, "some").unwrap();
+        assert_eq!(
+            pressure_over("cpu", &p, 10.0).as_deref(),
+            Some("cpu avg10=12.50")
+        );
+        // Only avg10 counts, and only above the threshold
+        assert_eq!(pressure_over("cpu", &p, 12.5), None);
+        assert_eq!(pressure_over("cpu", &p, 50.0), None);
+        assert_eq!(pressure_over("cpu", &Pressure::default(), 0.0), None);
+    }
+
+    #[test]
+    fn controllers_v2() {
+        let root = Path::new("/sys/fs/cgroup");
+        let subtree_control = root.join("bb_runner/cgroup.subtree_control");
+        let with_controllers = |enabled: &str| MockCgroupWriter {
+            files: HashMap::from([(subtree_control.clone(), enabled.to_string())]),
+            ..MockCgroupWriter::default()
+        };
+
+        let writer = with_controllers("cpuset cpu io memory pids\n");
+        assert_eq!(check_controllers(&writer, root, CgroupVersion::V2), Ok(()));
+        // Nothing is written, checking must not disturb a running job
+        assert_eq!(writer.ops(), []);
+
+        let writer = with_controllers("cpu io\n");
+        let err = check_controllers(&writer, root, CgroupVersion::V2).unwrap_err();
+        assert!(err.ends_with("lacks cpuset, memory"), "{err}");
+
+        let err = check_controllers(&MockCgroupWriter::default(), root, CgroupVersion::V2);
+        assert!(err.unwrap_err().contains("cgroup.subtree_control"));
+
+        let writer = MockCgroupWriter {
+            readonly: vec![root.join("bb_runner/cgroup.procs")],
+            ..with_controllers("cpuset memory")
+        };
+        let err = check_controllers(&writer, root, CgroupVersion::V2).unwrap_err();
+        assert!(err.contains("is not writable"), "{err}");
+    }
+
+    #[test]
+    fn controllers_v1() {
+        let root = Path::new("/sys/fs/cgroup");
+        let writer = MockCgroupWriter::default();
+        assert_eq!(check_controllers(&writer, root, CgroupVersion::V1), Ok(()));
+
+        let procs = CgroupPaths::runner_v1(root, "memory").join("cgroup.procs");
+        let writer = MockCgroupWriter {
+            readonly: vec![procs.clone()],
+            ..MockCgroupWriter::default()
+        };
+        let err = check_controllers(&writer, root, CgroupVersion::V1).unwrap_err();
+        assert!(err.starts_with(&format!("{procs:?}")), "{err}");
+    }
+
+    #[test]
+    fn memory_current() {
+        let job = Path::new("/sys/fs/cgroup/bb_runner/job0");
+        let writer = MockCgroupWriter {
+            files: HashMap::from([
+                (job.join("memory.current"), "1048576\n".to_string()),
+                (job.join("memory.usage_in_bytes"), "4096\n".to_string()),
+            ]),
+            ..MockCgroupWriter::default()
+        };
+        let read = |version| read_memory_current(&writer, version, job).unwrap();
+        assert_eq!(read(CgroupVersion::V2), 1 << 20);
+        assert_eq!(read(CgroupVersion::V1), 4096);
+
+        let writer = MockCgroupWriter {
+            files: HashMap::from([(job.join("memory.current"), "max\n".to_string())]),
+            ..MockCgroupWriter::default()
+        };
+        let err = read_memory_current(&writer, CgroupVersion::V2, job).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = read_memory_current(&writer, CgroupVersion::V1, job).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
@@ -1,25 +1,34 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Error, Result, Write};
-use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
-use std::os::unix::process::ExitStatusExt;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
 use std::process::{self, ExitStatus};
+use std::thread;
 use std::time::Duration;
 
-use tracing::{error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
-use nix::libc::{self, c_uint, pid_t, timeval};
+use nix::libc::{self, c_int, c_uint, pid_t, timeval};
 use nix::mount::{self, MsFlags};
 use nix::sched::{self, CloneFlags};
 use nix::sys::prctl;
+use nix::sys::resource::{self, Resource};
 use nix::sys::signal::{self, SaFlags, SigHandler, SigSet, SigmaskHow, Signal};
 use nix::unistd::{self, Gid, Pid, Uid};
 
+use crate::capabilities::drop_bounding_set;
+use crate::cgroup::setup_cgroup;
+use crate::config::{BindMountConfig, CgroupConfig, CoreDumpPolicy, NetworkPolicy};
+use crate::error::{NixResultExt, RunnerError, RunnerResult};
+use crate::features::FeatureProbe;
 use crate::mmaps::StackMap;
 use crate::mounts::{MntEntOpener, MntEntWrapper};
 use crate::resource::{ExitResources, ResourceUsage};
+use crate::seccomp::SeccompProfile;
 
 const RSS_MULTIPLIER: u64 = if cfg!(target_os = "macos") || cfg!(target_os = "ios") {
     1
@@ -41,22 +50,98 @@ pub(crate) trait Wait4 {
     fn try_wait4(&mut self) -> Result<Option<ExitResources>>;
 }
 
+/// Namespaces every child is cloned into, `hostname()`, `domainname()` and `uts_namespace()` add
+/// UTS, `cgroup()` and `cgroup_namespace()` add cgroup, `network()` can drop NET
+///
+/// The PID namespace only renumbers processes: pid1 is 1 inside and has its usual pid outside,
+/// every process of the job has one of each. The cgroup `pids.max` (set on the runner's cgroup by
+/// the operator, the runner doesn't write it) counts tasks, not pids of any one namespace, so a
+/// job is charged once per process however they are numbered, pid1 included. `pids.max = 2`
+/// leaves room for pid1 and the command, the command's first fork fails with EAGAIN, as it would
+/// with RLIMIT_NPROC. Nothing tells the two apart from the outside, the v2 `pids.events` `max`
+/// counter is the only trace of the cgroup limit being hit.
+pub(crate) const DEFAULT_NAMESPACES: CloneFlags = CloneFlags::CLONE_NEWPID
+    .union(CloneFlags::CLONE_NEWIPC)
+    .union(CloneFlags::CLONE_NEWNET)
+    .union(CloneFlags::CLONE_NEWNS)
+    .union(CloneFlags::CLONE_NEWUSER);
+
 #[derive(Debug)]
 pub(crate) struct Command {
     inner: process::Command,
     stdout: Option<File>,
     stderr: Option<File>,
     hostname: Option<String>,
-    cgroup: Option<String>,
+    domainname: Option<String>,
+    network: NetworkPolicy,
+    remount_readonly: bool,
+    remount_sysfs: bool,
+    time_namespace: bool,
+    bind_mounts: Vec<BindMountConfig>,
+    home_tmpfs: Option<(PathBuf, u32)>,
+    sysctls: HashMap<String, String>,
+    input_root: Option<PathBuf>,
+    rlimit_nproc: Option<u32>,
+    rlimit_fsize: Option<u64>,
+    rlimit_core: CoreDumpPolicy,
+    seccomp: Option<SeccompProfile>,
+    drop_capabilities: Vec<u32>,
+    no_new_privs: bool,
+    pdeathsig: Option<Signal>,
+    cpu_time_limit: Option<u32>,
+    cgroup: Option<(String, String, CgroupConfig)>,
     namespaces: CloneFlags,
 }
 
+/// pid1's ends of the pipes it synchronizes with the runner over
+struct SyncPipes {
+    /// Readable once the parent has configured the child, its uid_map and cgroup
+    ready_rx: OwnedFd,
+    /// Setup failures are written here, pid1 closes it once the command is spawned
+    error_tx: OwnedFd,
+}
+
+/// The runner's ends of [`SyncPipes`]
+struct SyncPipesParent {
+    ready_tx: OwnedFd,
+    error_rx: OwnedFd,
+}
+
+impl SyncPipes {
+    /// Both ends are close-on-exec, pid1 closes the rest of its copies before running the command
+    fn new() -> RunnerResult<(SyncPipes, SyncPipesParent)> {
+        let (ready_rx, ready_tx) = unistd::pipe2(OFlag::O_CLOEXEC).ctx("pipe2(ready pipe)")?;
+        let (error_rx, error_tx) = unistd::pipe2(OFlag::O_CLOEXEC).ctx("pipe2(error pipe)")?;
+        Ok((
+            SyncPipes { ready_rx, error_tx },
+            SyncPipesParent { ready_tx, error_rx },
+        ))
+    }
+}
+
 struct ChildData<'a> {
     cmd: &'a mut process::Command,
-    read_pipe: BorrowedFd<'a>,
+    pipes: &'a SyncPipes,
     stdout: Option<RawFd>,
     stderr: Option<RawFd>,
     hostname: Option<&'a str>,
+    domainname: Option<&'a str>,
+    loopback_up: bool,
+    remount_readonly: bool,
+    remount_sysfs: bool,
+    time_namespace: bool,
+    bind_mounts: &'a [BindMountConfig],
+    /// Home directory and size in MiB of its tmpfs
+    home_tmpfs: Option<(&'a Path, u32)>,
+    sysctls: &'a HashMap<String, String>,
+    input_root: Option<&'a Path>,
+    rlimit_nproc: Option<u32>,
+    rlimit_fsize: Option<u64>,
+    rlimit_core: CoreDumpPolicy,
+    seccomp: Option<&'a SeccompProfile>,
+    drop_capabilities: &'a [u32],
+    no_new_privs: bool,
+    pdeathsig: Option<Signal>,
 }
 
 impl std::convert::From<process::Command> for Command {
@@ -66,40 +151,97 @@ impl std::convert::From<process::Command> for Command {
             stdout: None,
             stderr: None,
             hostname: None,
+            domainname: None,
+            network: NetworkPolicy::LoopbackOnly,
+            remount_readonly: true,
+            remount_sysfs: true,
+            time_namespace: false,
+            bind_mounts: Vec::new(),
+            home_tmpfs: None,
+            sysctls: HashMap::new(),
+            input_root: None,
+            rlimit_nproc: None,
+            rlimit_fsize: None,
+            rlimit_core: CoreDumpPolicy::Disabled,
+            seccomp: None,
+            drop_capabilities: Vec::new(),
+            no_new_privs: true,
+            pdeathsig: Some(Signal::SIGKILL),
+            cpu_time_limit: None,
             cgroup: None,
-            namespaces: CloneFlags::CLONE_NEWPID
-                | CloneFlags::CLONE_NEWIPC
-                | CloneFlags::CLONE_NEWNET
-                | CloneFlags::CLONE_NEWNS
-                | CloneFlags::CLONE_NEWUSER,
+            namespaces: DEFAULT_NAMESPACES,
         }
     }
 }
 
 impl Command {
-    pub fn spawn(&mut self) -> Result<Child> {
-        let (read_pipe, write_pipe) = unistd::pipe2(OFlag::O_CLOEXEC)?;
-
-        let mut child_data = ChildData {
-            cmd: &mut self.inner,
-            read_pipe: read_pipe.as_fd(),
-            stdout: self.stdout.as_ref().map(|s| s.as_raw_fd()),
-            stderr: self.stderr.as_ref().map(|s| s.as_raw_fd()),
-            hostname: self.hostname.as_ref().map(String::as_ref),
+    /// Only the clone itself is synchronous, the cgroup setup that follows runs off the runtime
+    /// thread
+    pub async fn spawn(&mut self) -> RunnerResult<SpawnResult> {
+        let (pipes, parent) = SyncPipes::new()?;
+
+        let (pid, pidfd) = {
+            let mut child_data = ChildData {
+                cmd: &mut self.inner,
+                pipes: &pipes,
+                stdout: self.stdout.as_ref().map(|s| s.as_raw_fd()),
+                stderr: self.stderr.as_ref().map(|s| s.as_raw_fd()),
+                hostname: self.hostname.as_ref().map(String::as_ref),
+                domainname: self.domainname.as_ref().map(String::as_ref),
+                loopback_up: self.network == NetworkPolicy::LoopbackOnly,
+                remount_readonly: self.remount_readonly,
+                remount_sysfs: self.remount_sysfs,
+                time_namespace: self.time_namespace,
+                bind_mounts: &self.bind_mounts,
+                home_tmpfs: self.home_tmpfs.as_ref().map(|(p, mb)| (p.as_path(), *mb)),
+                sysctls: &self.sysctls,
+                input_root: self.input_root.as_deref(),
+                rlimit_nproc: self.rlimit_nproc,
+                rlimit_fsize: self.rlimit_fsize,
+                rlimit_core: self.rlimit_core,
+                seccomp: self.seccomp.as_ref(),
+                drop_capabilities: &self.drop_capabilities,
+                no_new_privs: self.no_new_privs,
+                pdeathsig: self.pdeathsig,
+            };
+            clone_pid1(self.namespaces, &mut child_data).map_err(RunnerError::Namespace)?
         };
+        drop(pipes);
+        let SyncPipesParent { ready_tx, error_rx } = parent;
 
-        let pid = clone_pid1(self.namespaces, &mut child_data)?;
-        drop(read_pipe);
-
-        write_uid_map(pid, unistd::getuid())?;
-        write_gid_map(pid, unistd::getgid())?;
-        if let Some(cg) = self.cgroup.as_ref().map(String::as_ref) {
-            move_child_cgroup(pid, cg)?;
+        let mut child = Child { pid, pidfd };
+        let res = match self.configure_child(pid, ready_tx).await {
+            Ok(cgroup_dir) => read_setup_error(error_rx).await.map(|()| cgroup_dir),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(cgroup_dir) => Ok(SpawnResult { child, cgroup_dir }),
+            Err(e) => {
+                // The child is still blocked on the pipe, it must not be left behind as a zombie
+                error!("Failed to configure child {}, killing it: {}", pid, e);
+                let _ = child.kill();
+                let _ = wait4(pid_t::from(pid), 0);
+                Err(e)
+            }
         }
+    }
 
-        unistd::write(write_pipe, "A".as_bytes())?;
+    /// Sets up the parts of the child that must be done from the parent, then unblocks it
+    ///
+    /// Returns the cgroup the child was moved into, if any
+    async fn configure_child(&self, pid: Pid, ready_tx: OwnedFd) -> RunnerResult<Option<PathBuf>> {
+        write_uid_map(pid, unistd::getuid()).map_err(RunnerError::Namespace)?;
+        write_gid_map(pid, unistd::getgid()).map_err(RunnerError::Namespace)?;
+        if let Some(secs) = self.cpu_time_limit {
+            set_cpu_time_limit(pid, secs).map_err(RunnerError::Namespace)?;
+        }
+        let cgroup_path = match self.cgroup.clone() {
+            Some((job, cpus, config)) => Some(setup_cgroup(pid, job, cpus, config).await?),
+            None => None,
+        };
 
-        Ok(Child { pid })
+        unistd::write(ready_tx, "A".as_bytes()).ctx("write(ready pipe)")?;
+        Ok(cgroup_path)
     }
 
     pub fn stdout(&mut self, f: File) -> &mut Command {
@@ -112,73 +254,220 @@ impl Command {
         self
     }
 
-    pub fn cgroup(&mut self, cg: &str) -> &mut Command {
-        self.cgroup = Some(cg.to_string());
+    /// Run in the cgroup of job slot `job`, restricted to `cpus`
+    pub fn cgroup(&mut self, job: &str, cpus: &str, config: &CgroupConfig) -> &mut Command {
+        self.cgroup = Some((job.to_string(), cpus.to_string(), config.clone()));
         self.namespaces |= CloneFlags::CLONE_NEWCGROUP;
         self
     }
 
+    /// Clone into a new cgroup namespace, `cgroup()` always does so `false` only matters
+    /// without it
+    pub fn cgroup_namespace(&mut self, isolate: bool) -> &mut Command {
+        if isolate {
+            self.namespaces |= CloneFlags::CLONE_NEWCGROUP;
+        }
+        self
+    }
+
+    /// Clone into a new UTS namespace, `hostname()` and `domainname()` always do so `false`
+    /// only matters without them
+    pub fn uts_namespace(&mut self, isolate: bool) -> &mut Command {
+        if isolate {
+            self.namespaces |= CloneFlags::CLONE_NEWUTS;
+        }
+        self
+    }
+
+    pub fn network(&mut self, policy: NetworkPolicy) -> &mut Command {
+        self.network = policy;
+        if policy == NetworkPolicy::FullNetwork {
+            self.namespaces.remove(CloneFlags::CLONE_NEWNET);
+        } else {
+            self.namespaces |= CloneFlags::CLONE_NEWNET;
+        }
+        self
+    }
+
+    /// Limit the CPU time of pid1 and the command, see [`set_cpu_time_limit`]
+    pub fn cpu_time_limit(&mut self, secs: Option<u32>) -> &mut Command {
+        self.cpu_time_limit = secs;
+        self
+    }
+
+    /// Remount everything read-only in the mount namespace, see [`remount_all_readonly`]
+    pub fn remount_readonly(&mut self, remount: bool) -> &mut Command {
+        self.remount_readonly = remount;
+        self
+    }
+
+    pub fn remount_sysfs(&mut self, remount: bool) -> &mut Command {
+        self.remount_sysfs = remount;
+        self
+    }
+
+    /// Run the command in its own time namespace, see [`unshare_time_namespace`]
+    pub fn time_namespace(&mut self, isolate: bool) -> &mut Command {
+        self.time_namespace = isolate;
+        self
+    }
+
+    /// Bind mounted after everything else was made read-only, see [`bind_mount`]
+    pub fn bind_mounts(&mut self, mounts: &[BindMountConfig]) -> &mut Command {
+        self.bind_mounts = mounts.to_vec();
+        self
+    }
+
+    /// Mounted over the home directory by pid1 after the bind mounts, see [`mount_home_tmpfs`]
+    pub fn home_tmpfs(&mut self, home: &Path, size_mb: u32) -> &mut Command {
+        self.home_tmpfs = Some((home.to_path_buf(), size_mb));
+        self
+    }
+
+    /// Written under `/proc/sys` by pid1, see [`write_sysctls`]
+    pub fn sysctls(&mut self, sysctls: &HashMap<String, String>) -> &mut Command {
+        self.sysctls = sysctls.clone();
+        self
+    }
+
+    /// RLIMIT_NPROC of the command, set by pid1 just before spawning it
+    pub fn rlimit_nproc(&mut self, limit: Option<u32>) -> &mut Command {
+        self.rlimit_nproc = limit;
+        self
+    }
+
+    /// RLIMIT_FSIZE of the command in MiB, set by pid1 just before spawning it
+    pub fn rlimit_fsize_mb(&mut self, limit: Option<u32>) -> &mut Command {
+        self.rlimit_fsize = limit.map(|mb| u64::from(mb) * 1024 * 1024);
+        self
+    }
+
+    /// RLIMIT_CORE of the command, set by pid1 just before spawning it
+    pub fn rlimit_core(&mut self, policy: CoreDumpPolicy) -> &mut Command {
+        self.rlimit_core = policy;
+        self
+    }
+
+    /// Capabilities removed from the bounding set by pid1, the command can't get them back
+    pub fn drop_capabilities(&mut self, caps: Vec<u32>) -> &mut Command {
+        self.drop_capabilities = caps;
+        self
+    }
+
+    /// Set no_new_privs in pid1, so neither the command nor anything it runs gains privileges
+    /// from setuid binaries or file capabilities
+    pub fn no_new_privs(&mut self, set: bool) -> &mut Command {
+        self.no_new_privs = set;
+        self
+    }
+
+    /// Seccomp filter of the command, installed between its fork from pid1 and the exec
+    pub fn seccomp(&mut self, profile: Option<SeccompProfile>) -> &mut Command {
+        self.seccomp = profile;
+        self
+    }
+
+    /// Signal pid1 gets when the runner dies, see [`reset_signals`]
+    pub fn pdeathsig(&mut self, signal: Option<Signal>) -> &mut Command {
+        self.pdeathsig = signal;
+        self
+    }
+
+    /// Checked to be accessible once the mounts are set up
+    pub fn input_root(&mut self, path: &Path) -> &mut Command {
+        self.input_root = Some(path.to_path_buf());
+        self
+    }
+
     pub fn hostname(&mut self, hostname: &str) -> &mut Command {
         self.hostname = Some(hostname.to_string());
         self.namespaces |= CloneFlags::CLONE_NEWUTS;
         self
     }
-}
 
-fn write_uid_map(pid: Pid, outer_uid: Uid) -> Result<()> {
-    let uid_map_path = format!("/proc/{pid}/uid_map");
-    let buf = format!("0 {outer_uid} 1");
-    File::create(uid_map_path).and_then(|mut f| f.write_all(buf.as_bytes()))
+    /// NIS domain name, the other name in the UTS namespace
+    pub fn domainname(&mut self, domainname: &str) -> &mut Command {
+        self.domainname = Some(domainname.to_string());
+        self.namespaces |= CloneFlags::CLONE_NEWUTS;
+        self
+    }
 }
 
-fn write_gid_map(pid: Pid, outer_gid: Gid) -> Result<()> {
-    let setgroups_path = format!("/proc/{pid}/setgroups");
-    File::create(setgroups_path).and_then(|mut f| f.write_all(b"deny"))?;
-
-    let gid_map_path = format!("/proc/{pid}/gid_map");
-    let buf = format!("0 {outer_gid} 1");
-    File::create(gid_map_path).and_then(|mut f| f.write_all(buf.as_bytes()))
-}
+/// Sets RLIMIT_CPU on pid1 before it forks the command, which inherits it
+///
+/// The limit is per process, not for the whole job. At the soft limit the kernel sends SIGXCPU,
+/// which `reset_signals` left at its default action of killing the process; the hard limit one
+/// second later sends SIGKILL in case the command ignores SIGXCPU.
+fn set_cpu_time_limit(pid: Pid, secs: u32) -> Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: libc::rlim_t::from(secs),
+        rlim_max: libc::rlim_t::from(secs) + 1,
+    };
 
-#[tracing::instrument(ret)]
-fn move_child_cgroup(pid: Pid, jobcpu: &str) -> Result<()> {
-    let cgroup_root = Path::new("/sys/fs/cgroup/bb_runner");
-    let cgroup_dir: PathBuf = cgroup_root.join(format!("job{jobcpu}"));
-    if !cgroup_dir.exists() {
-        std::fs::create_dir(&cgroup_dir)?;
+    match unsafe { libc::prlimit(pid.as_raw(), libc::RLIMIT_CPU, &limit, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
     }
+}
 
-    let cgproc = format!("{pid}");
-    OpenOptions::new()
-        .append(true)
-        .open(cgroup_dir.join("cgroup.procs"))
-        .and_then(|mut f| f.write_all(cgproc.as_bytes()))?;
+/// Not in nix, unlike `sethostname`
+fn set_domainname(name: &str) -> Result<()> {
+    let res = unsafe { libc::setdomainname(name.as_ptr().cast(), name.len()) };
+    Errno::result(res)?;
+    Ok(())
+}
 
+/// Like `fs::write` but without O_CREAT, for files in /proc and /sys
+fn write_existing_file<P: AsRef<Path>>(path: P, contents: &str) -> Result<()> {
     OpenOptions::new()
         .write(true)
-        .truncate(true)
-        .open(cgroup_dir.join("cpuset.cpus"))
-        .and_then(|mut f| f.write_all(jobcpu.as_bytes()))?;
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
 
-    OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(cgroup_dir.join("memory.swap.max"))
-        .and_then(|mut f| f.write_all(b"0"))?;
+/// A map of the sandbox's root to the single `outer` id, the one line of a `uid_map` or
+/// `gid_map`
+fn single_id_map(outer: impl std::fmt::Display) -> String {
+    format!("0 {outer} 1\n")
+}
 
-    OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(cgroup_dir.join("memory.max"))
-        .and_then(|mut f| f.write_all(b"1073741824"))?;
+fn write_uid_map(pid: Pid, outer_uid: Uid) -> Result<()> {
+    write_uid_map_at(Path::new(&format!("/proc/{pid}")), outer_uid)
+}
 
-    Ok(())
+fn write_gid_map(pid: Pid, outer_gid: Gid) -> Result<()> {
+    write_gid_map_at(Path::new(&format!("/proc/{pid}")), outer_gid)
+}
+
+/// `proc_dir` is the `/proc/<pid>` directory of the child
+fn write_uid_map_at(proc_dir: &Path, outer_uid: Uid) -> Result<()> {
+    let buf = single_id_map(outer_uid);
+    File::create(proc_dir.join("uid_map")).and_then(|mut f| f.write_all(buf.as_bytes()))
+}
+
+/// An unprivileged process may only write `gid_map` once `setgroups` is denied
+fn write_gid_map_at(proc_dir: &Path, outer_gid: Gid) -> Result<()> {
+    File::create(proc_dir.join("setgroups")).and_then(|mut f| f.write_all(b"deny"))?;
+
+    let buf = single_id_map(outer_gid);
+    File::create(proc_dir.join("gid_map")).and_then(|mut f| f.write_all(buf.as_bytes()))
+}
+
+/// Handler for a catchable parent death signal in pid1, passes it on to every other process in
+/// the pid namespace
+extern "C" fn forward_to_namespace(sig: c_int) {
+    unsafe { libc::kill(-1, sig) };
 }
 
-/// Resets all signal handlers and masks so nothing is inherited from parents
-/// Also sets parent death signal to SIGKILL
-fn reset_signals() -> Result<()> {
-    prctl::set_pdeathsig(Signal::SIGKILL)?;
+/// Resets what pid1 inherited from the runner, and arranges for `pdeathsig` when the runner dies
+///
+/// The kernel drops signals to a pid namespace's init that it has no handler for, except SIGKILL
+/// from an ancestor namespace. Any other `pdeathsig` gets a handler that forwards it to the job,
+/// pid1 itself exits once the command does.
+fn reset_signals(pdeathsig: Option<Signal>) -> Result<()> {
+    if let Some(sig) = pdeathsig {
+        prctl::set_pdeathsig(sig)?;
+    }
 
     signal::sigprocmask(SigmaskHow::SIG_SETMASK, Some(&SigSet::empty()), None)?;
 
@@ -199,6 +488,39 @@ fn reset_signals() -> Result<()> {
         }
     }
 
+    if let Some(sig) = pdeathsig.filter(|&s| s != Signal::SIGKILL) {
+        let forward = signal::SigAction::new(
+            SigHandler::Handler(forward_to_namespace),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { signal::sigaction(sig, &forward) }?;
+    }
+
+    Ok(())
+}
+
+/// A new network namespace only has `lo`, and it starts out down
+fn net_loopback_up() -> Result<()> {
+    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if sock < 0 {
+        return Err(Error::last_os_error());
+    }
+    let sock = unsafe { OwnedFd::from_raw_fd(sock) };
+
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
+    }
+
+    if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFFLAGS, &mut ifr) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    unsafe { ifr.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short };
+    if unsafe { libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFFLAGS, &ifr) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
     Ok(())
 }
 
@@ -210,32 +532,97 @@ fn close_range_fds(first: c_uint) -> Result<()> {
     }
 }
 
-fn remount_all_readonly() -> Result<()> {
-    let mntent = MntEntOpener::new(Path::new("/proc/self/mounts"))?;
-
-    let entries: Vec<MntEntWrapper> = mntent.list_all()?;
-    for ent in entries {
-        trace!("Mount Entry = {} = {:?}", ent.mnt_dir, ent);
-        if ent.mnt_dir.starts_with("/dev") {
-            continue;
+/// Closes every fd from `first` on, except `keep`
+fn close_fds_except(first: c_uint, keep: c_uint) -> Result<()> {
+    if keep < first {
+        return close_range_fds(first);
+    }
+    if keep > first {
+        match unsafe { nix::libc::close_range(first, keep - 1, 0) } {
+            0 => {}
+            -1 => return Err(Error::from(nix::errno::Errno::last())),
+            _ => return Err(Error::other("close_range failed")),
         }
+    }
+    close_range_fds(keep + 1)
+}
+
+/// Times a remount failing with EBUSY is retried, `REMOUNT_BUSY_DELAY` apart
+const REMOUNT_BUSY_RETRIES: u32 = 3;
+const REMOUNT_BUSY_DELAY: Duration = Duration::from_millis(10);
+
+/// `/dev` stays writable, and a mount that is read-only already is left alone
+fn needs_readonly_remount(ent: &MntEntWrapper) -> bool {
+    if ent.mnt_dir.starts_with("/dev") {
+        return false;
+    }
+    if ent.mnt_flags.contains(MsFlags::MS_RDONLY) {
+        trace!("Already read-only: {}", ent);
+        return false;
+    }
+    true
+}
 
+/// A remount applies to the topmost mount at a path, so mounts hidden under a later one at the
+/// same directory are left out. Remounting them would give pid1's own /proc the host's flags.
+fn mounts_to_remount(mounts: Vec<MntEntWrapper>) -> Vec<MntEntWrapper> {
+    let topmost: Vec<bool> = mounts
+        .iter()
+        .enumerate()
+        .map(|(i, ent)| !mounts[i + 1..].iter().any(|m| m.mnt_dir == ent.mnt_dir))
+        .collect();
+    mounts
+        .into_iter()
+        .zip(topmost)
+        .filter(|(ent, topmost)| {
+            trace!("Mount Entry = {}", ent);
+            *topmost && needs_readonly_remount(ent)
+        })
+        .map(|(ent, _)| ent)
+        .collect()
+}
+
+fn remount_all_readonly() -> Result<()> {
+    let mounts = MntEntOpener::new(Path::new("/proc/self/mounts"))?.list_all()?;
+
+    let mut skipped = 0;
+    for ent in mounts_to_remount(mounts) {
         // https://github.com/bazelbuild/bazel/blob/788b6080f54c6ca5093526023dfd9b12b90403f8/src/main/tools/linux-sandbox-pid1.cc#L346
         // MS_REMOUNT does not allow us to change certain flags. This means, we have
         // to first read them out and then pass them in back again. There seems to
         // be no better way than this (an API for just getting the mount flags of a
         // mount entry as a bitmask would be great).
 
-        match mount::mount(
-            None::<&'static str>,
-            Path::new(ent.mnt_dir.as_str()),
-            None::<&'static str>,
-            ent.mnt_flags | MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
-            None::<&'static str>,
-        ) {
+        let remount = || {
+            mount::mount(
+                None::<&'static str>,
+                Path::new(ent.mnt_dir.as_str()),
+                None::<&'static str>,
+                ent.mnt_flags | MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+                None::<&'static str>,
+            )
+        };
+        let mut result = remount();
+        for _ in 0..REMOUNT_BUSY_RETRIES {
+            if result != Err(Errno::EBUSY) {
+                break;
+            }
+            thread::sleep(REMOUNT_BUSY_DELAY);
+            result = remount();
+        }
+
+        match result {
             Ok(_) => {}
-            Err(Errno::EACCES) | Err(Errno::EPERM) | Err(Errno::EINVAL) | Err(Errno::ENOENT)
-            | Err(Errno::ESTALE) | Err(Errno::ENODEV) => {
+            // Still in use after the retries, e.g. by a daemon of the host
+            Err(Errno::EBUSY) => {
+                warn!("Failed to remount {}, busy, ignored", ent.mnt_dir);
+            }
+            // Mounts locked by the outer user namespace, expected in containers
+            Err(Errno::EACCES) | Err(Errno::EPERM) => {
+                trace!("Failed to remount {}, ignored", ent.mnt_dir);
+                skipped += 1;
+            }
+            Err(Errno::EINVAL) | Err(Errno::ENOENT) | Err(Errno::ESTALE) | Err(Errno::ENODEV) => {
                 // See: https://github.com/bazelbuild/bazel/blob/788b6080f54c6ca5093526023dfd9b12b90403f8/src/main/tools/linux-sandbox-pid1.cc#L376
                 info!("Failed to remount {}, ignored", ent.mnt_dir);
             }
@@ -245,24 +632,325 @@ fn remount_all_readonly() -> Result<()> {
             }
         }
     }
+    if skipped > 0 {
+        debug!("Skipped {} read-only remounts (EACCES/EPERM)", skipped);
+    }
+
+    Ok(())
+}
+
+/// Makes `/sys` read-only and nosuid/noexec/nodev, in case the generic remount kept any of
+/// those flags off
+fn remount_sysfs_readonly() -> Result<()> {
+    let mntent = MntEntOpener::new(Path::new("/proc/self/mounts"))?;
+    // Flags locked by the user namespace, like the atime ones, must be passed back in
+    let Some(sysfs) = mntent.list_all()?.into_iter().find(|e| e.mnt_dir == "/sys") else {
+        debug!("/sys is not mounted, not remounting it");
+        return Ok(());
+    };
+
+    let flags = sysfs.mnt_flags
+        | MsFlags::MS_RDONLY
+        | MsFlags::MS_NOSUID
+        | MsFlags::MS_NOEXEC
+        | MsFlags::MS_NODEV
+        | MsFlags::MS_BIND
+        | MsFlags::MS_REMOUNT;
+
+    match mount::mount(
+        None::<&'static str>,
+        "/sys",
+        None::<&'static str>,
+        flags,
+        None::<&'static str>,
+    ) {
+        Ok(_) => Ok(()),
+        Err(Errno::ENOENT) => {
+            debug!("/sys does not exist, not remounting it");
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Best effort, sysctls that are not namespaced can't be written and don't fail the job
+fn write_sysctls(sysctls: &HashMap<String, String>) {
+    for (key, value) in sysctls {
+        let path = Path::new("/proc/sys").join(key);
+        if let Err(e) = write_existing_file(&path, value) {
+            warn!("Failed to set sysctl {} = {:?}: {}", key, value, e);
+        }
+    }
+}
+
+/// Binds `host_path` to `sandbox_path`, then remounts it read-only or read-write
+///
+/// A new bind mount inherits the flags of its source, which `remount_all_readonly` already made
+/// read-only, so writable mounts need the remount too. The flags the outer user namespace locked,
+/// like noexec and the atime ones, are read back from the mount table and passed in again, the
+/// remount fails with EPERM otherwise.
+fn bind_mount(bind: &BindMountConfig) -> Result<()> {
+    mount::mount(
+        Some(&bind.host_path),
+        &bind.sandbox_path,
+        None::<&'static str>,
+        MsFlags::MS_BIND,
+        None::<&'static str>,
+    )?;
+
+    let mounts = MntEntOpener::new(Path::new("/proc/self/mounts"))?.list_all()?;
+    // The new bind is the last entry at its path, it is over anything mounted there before
+    let inherited = mounts
+        .iter()
+        .rev()
+        .find(|m| Path::new(&m.mnt_dir) == bind.sandbox_path)
+        .map_or(MsFlags::empty(), |m| m.mnt_flags);
+
+    let mut flags = (inherited - MsFlags::MS_RDONLY)
+        | MsFlags::MS_REMOUNT
+        | MsFlags::MS_BIND
+        | MsFlags::MS_NOSUID
+        | MsFlags::MS_NODEV;
+    if bind.readonly {
+        flags |= MsFlags::MS_RDONLY;
+    }
+    mount::mount(
+        None::<&'static str>,
+        &bind.sandbox_path,
+        None::<&'static str>,
+        flags,
+        None::<&'static str>,
+    )?;
+    Ok(())
+}
+
+/// Directories below the home that spawn_child creates for the XDG variables, the tmpfs hides
+/// them so they are made again on it
+const HOME_SUBDIRS: [&str; 3] = [".cache", ".config", ".local/share"];
+
+/// A size limited tmpfs on the job's home, it is private to the job and gone with its mount
+/// namespace
+fn mount_home_tmpfs(home: &Path, size_mb: u32) -> Result<()> {
+    mount::mount(
+        Some("tmpfs"),
+        home,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(format!("size={size_mb}m,mode=0700").as_str()),
+    )?;
+    for dir in HOME_SUBDIRS {
+        fs::create_dir_all(home.join(dir))?;
+    }
+    Ok(())
+}
+
+/// Waits for pid1 to finish its setup and spawn the command, EOF without anything written means
+/// it succeeded. A failure is reported as the code from [`CHILD_ERROR_CODES`], a newline, and
+/// the error.
+async fn read_setup_error(error_pipe: OwnedFd) -> RunnerResult<()> {
+    let msg = tokio::task::spawn_blocking(move || {
+        let mut msg = String::new();
+        File::from(error_pipe).read_to_string(&mut msg).map(|_| msg)
+    })
+    .await
+    .map_err(|e| RunnerError::Namespace(Error::other(e)))?
+    .map_err(RunnerError::Namespace)?;
+
+    if msg.is_empty() {
+        return Ok(());
+    }
+    let (code, message) = match msg.split_once('\n') {
+        Some((code, message)) => (code.parse().unwrap_or(-1), message.to_string()),
+        None => (-1, msg),
+    };
+    Err(RunnerError::ChildSetup { code, message })
+}
+
+/// Checks the mount table pid1 ended up with, mounts that failed silently would otherwise only
+/// show up as confusing build failures
+fn validate_mounts(child_data: &ChildData) -> Result<()> {
+    let mounts = MntEntOpener::new(Path::new("/proc/self/mounts"))?.list_all()?;
+    // Later entries are mounted over earlier ones
+    let mount_at = |dir: &Path| mounts.iter().rev().find(|m| Path::new(&m.mnt_dir) == dir);
+    let mut problems = Vec::new();
+
+    let proc_flags = MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC | MsFlags::MS_NODEV;
+    match mount_at(Path::new("/proc")) {
+        Some(m) if m.mnt_type == "proc" && m.mnt_flags.contains(proc_flags) => {}
+        Some(m) => problems.push(format!("/proc is mounted as {m}")),
+        None => problems.push("/proc is not mounted".to_string()),
+    }
+
+    for bind in child_data.bind_mounts {
+        match mount_at(&bind.sandbox_path) {
+            Some(m) if m.mnt_flags.contains(MsFlags::MS_RDONLY) == bind.readonly => {}
+            Some(m) => problems.push(format!("{:?} is mounted as {m}", bind.sandbox_path)),
+            None => problems.push(format!("{:?} is not mounted", bind.sandbox_path)),
+        }
+    }
+
+    if let Some((home, _)) = child_data.home_tmpfs {
+        match mount_at(home) {
+            Some(m) if m.mnt_type == "tmpfs" => {}
+            Some(m) => problems.push(format!("{home:?} is mounted as {m}")),
+            None => problems.push(format!("{home:?} is not mounted")),
+        }
+    }
+
+    if let Some(ird) = child_data.input_root {
+        if let Err(e) = unistd::access(ird, unistd::AccessFlags::X_OK) {
+            problems.push(format!("input root {ird:?} is not accessible: {e}"));
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(Error::other(format!(
+        "mount validation failed: {}",
+        problems.join(", ")
+    )))
+}
 
+/// Not in nix yet, from linux/sched.h. `from_bits_retain` because `from_bits_truncate` would drop
+/// the unknown bit.
+const CLONE_NEWTIME: CloneFlags = CloneFlags::from_bits_retain(0x80);
+
+/// Creates a time namespace that pid1's children are created in, with `offsets` from
+/// [`timens_offsets`]
+///
+/// It can't be a `clone_pid1` flag, clone(2) takes the exit signal in the low byte that 0x80 is
+/// part of. Unlike other namespaces unsharing doesn't move pid1 itself, only its children, and
+/// the offsets can only be written before the first child is created.
+fn unshare_time_namespace(offsets: &str) -> Result<()> {
+    sched::unshare(CLONE_NEWTIME)?;
+    fs::write("/proc/self/timens_offsets", offsets)?;
     Ok(())
 }
 
-fn child_pid1(child_data: &mut ChildData) -> Result<isize> {
+fn clock_now(clock: libc::clockid_t) -> Result<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock, &mut ts) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// The `timens_offsets` that start the monotonic and boottime clocks near zero, so the job
+/// can't read the host's uptime from them
+fn timens_offsets() -> Result<String> {
+    let monotonic = clock_now(libc::CLOCK_MONOTONIC)?;
+    let boottime = clock_now(libc::CLOCK_BOOTTIME)?;
+    Ok(format!(
+        "monotonic {}\nboottime {}\n",
+        negative_offset(monotonic),
+        negative_offset(boottime)
+    ))
+}
+
+/// `-now` as the kernel takes it, whole seconds that may be negative and nanoseconds that may
+/// not. The clock goes on between reading and writing it, so the namespace's never goes below 0.
+fn negative_offset(now: Duration) -> String {
+    let secs = now.as_secs() as i64;
+    match now.subsec_nanos() {
+        0 => format!("{} 0", -secs),
+        nanos => format!("{} {}", -secs - 1, 1_000_000_000 - nanos),
+    }
+}
+
+/// Whether a time namespace can be created, it needs Linux 5.6 or later
+pub(crate) fn probe_time_namespace() -> bool {
+    let unshare = || match sched::unshare(CLONE_NEWTIME) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    };
+    probe_clone(CloneFlags::CLONE_NEWUSER, unshare).unwrap_or(false)
+}
+
+/// pid1's exit codes, and the codes it reports to the parent, when setting up the sandbox failed
+pub(crate) const CHILD_ERROR_CODES: &[(isize, &str)] = &[
+    (CHILD_SETUP_FAILED, "namespace setup failed"),
+    (CHILD_PROC_MOUNT_FAILED, "mounting /proc failed"),
+    (CHILD_REMOUNT_FAILED, "setting up the mounts failed"),
+    (CHILD_EXEC_FAILED, "executing the command failed"),
+];
+
+const CHILD_SETUP_FAILED: isize = -2;
+pub(crate) const CHILD_PROC_MOUNT_FAILED: isize = -3;
+pub(crate) const CHILD_REMOUNT_FAILED: isize = -4;
+pub(crate) const CHILD_EXEC_FAILED: isize = -5;
+
+/// Description of a [`CHILD_ERROR_CODES`] code
+pub(crate) fn child_error_description(code: isize) -> &'static str {
+    CHILD_ERROR_CODES
+        .iter()
+        .find(|&&(c, _)| c == code)
+        .map_or("child setup failed", |&(_, desc)| desc)
+}
+
+/// Tags a setup step's error with the code of the stage it belongs to
+trait SetupStage<T> {
+    fn stage(self, code: isize) -> std::result::Result<T, (isize, Error)>;
+}
+
+impl<T, E: Into<Error>> SetupStage<T> for std::result::Result<T, E> {
+    fn stage(self, code: isize) -> std::result::Result<T, (isize, Error)> {
+        self.map_err(|e| (code, e.into()))
+    }
+}
+
+/// The rlimits of the command, set in pid1 so the command and everything it runs inherit them
+fn set_rlimits(nproc: Option<u32>, fsize: Option<u64>, core: CoreDumpPolicy) -> nix::Result<()> {
+    // pid1 itself counts, so the command can have one process less than the limit
+    if let Some(limit) = nproc {
+        resource::setrlimit(Resource::RLIMIT_NPROC, limit.into(), limit.into())?;
+    }
+    if let Some(limit) = fsize {
+        resource::setrlimit(Resource::RLIMIT_FSIZE, limit, limit)?;
+    }
+    let (_, core_hard) = resource::getrlimit(Resource::RLIMIT_CORE)?;
+    let core_limit = match core {
+        CoreDumpPolicy::Disabled => 0,
+        CoreDumpPolicy::Unlimited => core_hard,
+        CoreDumpPolicy::MaxBytes(n) => n.min(core_hard),
+    };
+    resource::setrlimit(Resource::RLIMIT_CORE, core_limit, core_limit)
+}
+
+fn child_pid1(child_data: &mut ChildData) -> isize {
+    match run_pid1(child_data) {
+        Ok(code) => code,
+        Err((code, e)) => {
+            error!("{}: {}", child_error_description(code), e);
+            // Nothing to do if even that fails, the parent still sees the exit code
+            let report = format!("{code}\n{e}");
+            let _ = unistd::write(&child_data.pipes.error_tx, report.as_bytes());
+            code
+        }
+    }
+}
+
+fn run_pid1(child_data: &mut ChildData) -> std::result::Result<isize, (isize, Error)> {
     let pid = Pid::this();
-    nix::unistd::setpgid(pid, pid)?;
-    reset_signals()?;
+    nix::unistd::setpgid(pid, pid).stage(CHILD_SETUP_FAILED)?;
+    reset_signals(child_data.pdeathsig).stage(CHILD_SETUP_FAILED)?;
+    // Inherited by every exec and child, and it can't be unset again
+    if child_data.no_new_privs {
+        prctl::set_no_new_privs().stage(CHILD_SETUP_FAILED)?;
+    }
 
     info!("In child, pid = {}, ppid = {}", pid, Pid::parent());
 
     // Block until the parent has configured our uid_map
     let mut buf = [0; 4];
-    let _ = unistd::read(child_data.read_pipe.as_raw_fd(), &mut buf);
+    let _ = unistd::read(child_data.pipes.ready_rx.as_raw_fd(), &mut buf);
     info!("Read from pipe: {:?}", buf);
 
     // cd / before mounting in case we were keeping something busy
-    unistd::chdir("/")?;
+    unistd::chdir("/").stage(CHILD_SETUP_FAILED)?;
 
     // Fully isolate our namespace from parent
     mount::mount(
@@ -271,10 +959,18 @@ fn child_pid1(child_data: &mut ChildData) -> Result<isize> {
         None::<&'static str>,
         MsFlags::MS_REC | MsFlags::MS_PRIVATE,
         None::<&'static str>,
-    )?;
+    )
+    .stage(CHILD_SETUP_FAILED)?;
 
     if let Some(h) = child_data.hostname {
-        unistd::sethostname(h)?;
+        unistd::sethostname(h).stage(CHILD_SETUP_FAILED)?;
+    }
+    if let Some(d) = child_data.domainname {
+        set_domainname(d).stage(CHILD_SETUP_FAILED)?;
+    }
+
+    if child_data.loopback_up {
+        net_loopback_up().stage(CHILD_SETUP_FAILED)?;
     }
 
     let mount_flags = MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC | MsFlags::MS_NODEV;
@@ -284,74 +980,388 @@ fn child_pid1(child_data: &mut ChildData) -> Result<isize> {
         Some("proc"),
         mount_flags,
         None::<&'static str>,
-    )?;
+    )
+    .stage(CHILD_PROC_MOUNT_FAILED)?;
+
+    // Before everything, including /proc, is remounted read-only
+    write_sysctls(child_data.sysctls);
+    if child_data.time_namespace {
+        let offsets = timens_offsets().stage(CHILD_SETUP_FAILED)?;
+        unshare_time_namespace(&offsets).stage(CHILD_SETUP_FAILED)?;
+    }
 
-    remount_all_readonly()?;
+    // The namespace is private either way, only the flags of the mounts stay as they are
+    if child_data.remount_readonly {
+        remount_all_readonly().stage(CHILD_REMOUNT_FAILED)?;
+    }
+    if child_data.remount_sysfs {
+        remount_sysfs_readonly().stage(CHILD_REMOUNT_FAILED)?;
+    }
+    for bind in child_data.bind_mounts {
+        debug!(
+            "Bind mounting {:?} at {:?}",
+            bind.host_path, bind.sandbox_path
+        );
+        bind_mount(bind).stage(CHILD_REMOUNT_FAILED)?;
+    }
+    if let Some((home, size_mb)) = child_data.home_tmpfs {
+        mount_home_tmpfs(home, size_mb).stage(CHILD_REMOUNT_FAILED)?;
+    }
+    validate_mounts(child_data).stage(CHILD_REMOUNT_FAILED)?;
 
     info!("From child!! pid = {} uid = {}", pid, unistd::getuid());
 
-    // Setup child stdio and close everything else
+    // Setup child stdio and close everything else, except the error pipe until the command runs
     if let Some(stdout) = child_data.stdout {
-        let _ = unistd::dup2(stdout, libc::STDOUT_FILENO)?;
+        let _ = unistd::dup2(stdout, libc::STDOUT_FILENO).stage(CHILD_SETUP_FAILED)?;
     }
     if let Some(stderr) = child_data.stderr {
-        let _ = unistd::dup2(stderr, libc::STDERR_FILENO)?;
+        let _ = unistd::dup2(stderr, libc::STDERR_FILENO).stage(CHILD_SETUP_FAILED)?;
+    }
+    close_fds_except(
+        (libc::STDERR_FILENO as c_uint) + 1,
+        child_data.pipes.error_tx.as_raw_fd() as c_uint,
+    )
+    .stage(CHILD_SETUP_FAILED)?;
+
+    set_rlimits(
+        child_data.rlimit_nproc,
+        child_data.rlimit_fsize,
+        child_data.rlimit_core,
+    )
+    .stage(CHILD_SETUP_FAILED)?;
+
+    // pid1 keeps its own capabilities, the command loses them when it is executed
+    drop_bounding_set(child_data.drop_capabilities).stage(CHILD_SETUP_FAILED)?;
+    // Only the command is filtered, pid1 keeps every syscall it needs to wait and forward signals
+    if let Some(profile) = child_data.seccomp {
+        let profile = profile.clone();
+        unsafe { child_data.cmd.pre_exec(move || profile.apply()) };
     }
-    close_range_fds((libc::STDERR_FILENO as c_uint) + 1)?;
 
-    let mut child = child_data.cmd.spawn()?;
+    // The error pipe is O_CLOEXEC, the command doesn't inherit it
+    let mut child = child_data.cmd.spawn().stage(CHILD_EXEC_FAILED)?;
 
-    // File descriptors are for child, close everything in pid1
-    close_range_fds(0)?;
-    let exitstatus = child.wait()?;
+    // File descriptors are for child, close everything in pid1, the parent sees EOF on the
+    // error pipe now
+    close_range_fds(0).stage(-1)?;
+    let exitstatus = child.wait().stage(-1)?;
 
     // Child was killed, kill ourselves the same way to propagate upwards
     if let Some(sigi32) = exitstatus.signal() {
-        let sig = Signal::try_from(sigi32)?;
-        signal::kill(unistd::getpid(), Some(sig))?;
+        let sig = Signal::try_from(sigi32).stage(-1)?;
+        signal::kill(unistd::getpid(), Some(sig)).stage(-1)?;
     }
 
     // Return childs code upwards
-    Ok(exitstatus.code().ok_or(Error::other("Child failed"))? as isize)
+    Ok(exitstatus
+        .code()
+        .ok_or(Error::other("Child failed"))
+        .stage(-1)? as isize)
 }
 
-fn clone_pid1(clone_flags: CloneFlags, child_data: &mut ChildData) -> Result<Pid> {
-    let stack = StackMap::new(1024 * 1024)?; // 1 MB stacks
+/// [`sched::clone`] with CLONE_PIDFD, nix has no way to pass the pointer the pidfd is written to
+///
+/// Kernels before 5.2 ignore the flag, the pidfd is `None` there.
+///
+/// # Safety
+///
+/// Same as [`sched::clone`].
+unsafe fn clone_with_pidfd(
+    mut cb: sched::CloneCb,
+    stack: &mut [u8],
+    flags: CloneFlags,
+    signal: Option<c_int>,
+) -> nix::Result<(Pid, Option<PidFd>)> {
+    extern "C" fn callback(data: *mut libc::c_void) -> c_int {
+        let cb = unsafe { &mut *(data as *mut sched::CloneCb) };
+        cb() as c_int
+    }
+
+    let mut pidfd: c_int = -1;
+    let combined = flags.bits() | libc::CLONE_PIDFD | signal.unwrap_or(0);
+    let res = unsafe {
+        let ptr = stack.as_mut_ptr().add(stack.len());
+        let ptr_aligned = ptr.sub(ptr as usize % 16);
+        libc::clone(
+            callback,
+            ptr_aligned as *mut libc::c_void,
+            combined,
+            &mut cb as *mut sched::CloneCb as *mut libc::c_void,
+            &mut pidfd as *mut c_int,
+        )
+    };
+    let pid = Errno::result(res).map(Pid::from_raw)?;
+    let pidfd = (pidfd >= 0).then(|| PidFd(unsafe { OwnedFd::from_raw_fd(pidfd) }));
+    Ok((pid, pidfd))
+}
+
+/// Clones pid1, with a pidfd where the kernel has them, so it can't be mistaken for another
+/// process reusing its pid
+fn clone_pid1(clone_flags: CloneFlags, child_data: &mut ChildData) -> Result<(Pid, Option<PidFd>)> {
+    let mut stack = StackMap::from_size_bytes(1024 * 1024)?; // 1 MB stacks
     info!("Stack: {:?}", stack);
 
     let sig = Some(Signal::SIGCHLD as i32);
+    let cb: sched::CloneCb = Box::new(move || child_pid1(child_data));
 
-    let child_pid = unsafe {
-        sched::clone(
-            Box::new(move || child_pid1(child_data).unwrap_or(-1)),
-            stack.as_slice()?,
-            clone_flags,
-            sig,
-        )
+    let res = if FeatureProbe::get().pidfd {
+        unsafe { clone_with_pidfd(cb, stack.as_slice()?, clone_flags, sig) }
+    } else {
+        unsafe { sched::clone(cb, stack.as_slice()?, clone_flags, sig) }.map(|pid| (pid, None))
+    };
+    let child = match res {
+        Ok(child) => child,
+        Err(Errno::EPERM) => {
+            let diagnosis = diagnose_clone_eperm();
+            error!("clone failed with EPERM: {}", diagnosis);
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{}: {diagnosis}", Errno::EPERM),
+            ));
+        }
+        Err(e) => return Err(e.into()),
     };
 
-    Ok(child_pid?)
+    // Without CLONE_VM the child got its own copy, release ours so nothing lingers in memory
+    if let Err(e) = stack.clear() {
+        error!("Failed to clear clone stack: {}", e);
+    }
+
+    Ok(child)
+}
+
+/// Why creating the user namespace may have been refused, from the sysctls that restrict it and
+/// whether the runner is itself in a user namespace. Only for errors, it reads several files.
+fn diagnose_clone_eperm() -> String {
+    let read = |path: &str| fs::read_to_string(path).ok().map(|s| s.trim().to_string());
+    let mut findings = Vec::new();
+
+    match read("/proc/sys/user/max_user_namespaces").as_deref() {
+        Some("0") => findings.push("user.max_user_namespaces is 0".to_string()),
+        Some(n) => findings.push(format!("user.max_user_namespaces is {n}")),
+        None => findings.push("user.max_user_namespaces is unreadable".to_string()),
+    }
+    // Debian and older Ubuntu kernels
+    if read("/proc/sys/kernel/unprivileged_userns_clone").as_deref() == Some("0") {
+        findings.push("kernel.unprivileged_userns_clone is 0".to_string());
+    }
+    // Ubuntu 23.10 and later
+    if read("/proc/sys/kernel/apparmor_restrict_unprivileged_userns").as_deref() == Some("1") {
+        findings.push(
+            "kernel.apparmor_restrict_unprivileged_userns is 1, an AppArmor profile must allow \
+             userns"
+                .to_string(),
+        );
+    }
+    // The initial user namespace maps every uid onto itself
+    match read("/proc/self/uid_map") {
+        Some(map) if map.split_whitespace().collect::<Vec<_>>() == ["0", "0", "4294967295"] => {}
+        Some(map) => findings.push(format!(
+            "the runner is in a user namespace (uid_map {:?}), its owner may not allow nesting",
+            map.split_whitespace().collect::<Vec<_>>().join(" ")
+        )),
+        None => {}
+    }
+    if !unistd::geteuid().is_root() {
+        findings.push(format!(
+            "running as uid {}, so unprivileged user namespaces must be allowed",
+            unistd::geteuid()
+        ));
+    }
+
+    findings.join("; ")
+}
+
+/// Clone a child that exits immediately, to check the kernel allows the namespaces
+/// Runs `f` in a short lived child cloned with `clone_flags`
+///
+/// Returns whether `f` exited with 0, an error means the clone itself failed.
+pub(crate) fn probe_clone<F: FnMut() -> isize>(clone_flags: CloneFlags, f: F) -> Result<bool> {
+    let mut stack = StackMap::from_size_bytes(64 * 1024)?;
+    let sig = Some(Signal::SIGCHLD as i32);
+
+    let pid = unsafe { sched::clone(Box::new(f), stack.as_slice()?, clone_flags, sig) }?;
+    let exit = wait4(pid_t::from(pid), 0)?;
+
+    Ok(exit.is_some_and(|e| e.status.success()))
+}
+
+/// Checks each namespace in `namespaces` individually.
+///
+/// Every probe also creates a user namespace, the others can't be created unprivileged without
+/// one, same as when spawning real children.
+pub(crate) fn probe_namespace_support(namespaces: CloneFlags) -> Vec<(CloneFlags, bool)> {
+    namespaces
+        .iter()
+        .map(|ns| {
+            let res = probe_clone(ns | CloneFlags::CLONE_NEWUSER, || 0);
+            if let Err(ref e) = res {
+                error!("Namespace probe {:?} failed: {}", ns, e);
+            }
+            (ns, res.is_ok())
+        })
+        .collect()
+}
+
+/// Logs where the kernel puts the core dumps of jobs under `policy`
+///
+/// RLIMIT_CORE doesn't apply to a `|handler` pattern, that one gets every dump even when they
+/// are disabled, but outside the sandbox. A file pattern is relative to the crashing process's
+/// working directory, so enabled dumps end up in the build directory.
+pub(crate) fn check_core_pattern(policy: CoreDumpPolicy) {
+    let pattern = match fs::read_to_string("/proc/sys/kernel/core_pattern") {
+        Ok(pattern) => pattern.trim_end().to_string(),
+        Err(e) => {
+            debug!("Failed to read core_pattern: {}", e);
+            return;
+        }
+    };
+
+    match (policy, pattern.starts_with('|')) {
+        (CoreDumpPolicy::Disabled, true) => info!(
+            "Core dumps of jobs are disabled, but core_pattern {:?} still receives them",
+            pattern
+        ),
+        (CoreDumpPolicy::Disabled, false) => {}
+        (_, true) => info!("Core dumps of jobs go to {:?}", pattern),
+        (_, false) => warn!(
+            "Core dumps of jobs are written to {:?} inside the sandbox",
+            pattern
+        ),
+    }
 }
 
-#[derive(Debug)]
 pub(crate) struct Child {
     pid: Pid,
+    /// Refers to pid1 even after it exited, `None` on kernels without CLONE_PIDFD
+    pidfd: Option<PidFd>,
+}
+
+/// Shows the state of the process from `/proc/<pid>/stat`, to tell a stuck one from a zombie
+impl std::fmt::Debug for Child {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.pid)).ok();
+        // The state follows the command name, which is in parentheses and may contain any byte
+        let state = stat
+            .as_deref()
+            .and_then(|stat| stat.rsplit_once(')'))
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .map_or_else(
+                || "<exited>".to_string(),
+                |state| match state {
+                    "R" => "R (running)".to_string(),
+                    "S" => "S (sleeping)".to_string(),
+                    "D" => "D (uninterruptible)".to_string(),
+                    "Z" => "Z (zombie)".to_string(),
+                    "T" => "T (stopped)".to_string(),
+                    "t" => "t (tracing stop)".to_string(),
+                    "X" => "X (dead)".to_string(),
+                    "I" => "I (idle)".to_string(),
+                    other => other.to_string(),
+                },
+            );
+        f.debug_struct("Child")
+            .field("pid", &pid_t::from(self.pid))
+            .field("state", &state)
+            .finish()
+    }
+}
+
+/// A started child and the cgroup it was moved into, if any
+#[derive(Debug)]
+pub(crate) struct SpawnResult {
+    pub child: Child,
+    pub cgroup_dir: Option<PathBuf>,
+}
+
+/// Children of the calling thread, zombies included, pid1 is cloned from the thread spawning it
+#[cfg(test)]
+pub(crate) fn children_of_this_thread() -> String {
+    fs::read_to_string("/proc/thread-self/children").unwrap()
 }
 
 impl Child {
+    /// A process the tests started themselves, signalled without a pidfd
+    #[cfg(test)]
+    pub fn from_pid(pid: Pid) -> Self {
+        Self { pid, pidfd: None }
+    }
+
     pub fn id(&self) -> u32 {
         pid_t::from(self.pid) as u32
     }
 
+    /// SIGTERM to the process group of pid1, which the command inherited
+    ///
+    /// pid1 normally has no handler, so the kernel doesn't deliver it there; it just exits once
+    /// the command does. With a SIGTERM `pdeathsig` it forwards it again, which is harmless.
+    pub fn terminate(&mut self) -> Result<()> {
+        Ok(signal::killpg(self.pid, Some(Signal::SIGTERM))?)
+    }
+
     pub fn kill(&mut self) -> Result<()> {
-        Ok(signal::kill(self.pid, Some(Signal::SIGKILL))?)
+        let Some(pidfd) = &self.pidfd else {
+            return Ok(signal::kill(self.pid, Some(Signal::SIGKILL))?);
+        };
+        let r = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                pidfd.as_raw_fd(),
+                libc::SIGKILL,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+        if r < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// A pidfd of pid1, it becomes readable once pid1 exited
+    pub fn pidfd(&self) -> Result<PidFd> {
+        if let Some(pidfd) = &self.pidfd {
+            return Ok(PidFd(pidfd.0.try_clone()?));
+        }
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid_t::from(self.pid), 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(PidFd(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct PidFd(OwnedFd);
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
     }
 }
 
-#[allow(clippy::useless_conversion)]
+/// Negative fields are clamped to zero, and the sum saturates instead of overflowing
 fn timeval_to_duration(val: timeval) -> Duration {
-    let v = i64::from(val.tv_sec) * 1_000_000 + i64::from(val.tv_usec);
-    Duration::from_micros(v as u64)
+    let secs = u64::try_from(val.tv_sec).unwrap_or(0);
+    let usecs = u64::try_from(val.tv_usec).unwrap_or(0);
+    Duration::from_secs(secs).saturating_add(Duration::from_micros(usecs))
+}
+
+/// From the wait status and rusage of a reaped child, the cgroup's counters are added later
+fn exit_resources(status: c_int, rusage: &libc::rusage) -> ExitResources {
+    ExitResources {
+        status: ExitStatus::from_raw(status),
+        rusage: ResourceUsage {
+            utime: timeval_to_duration(rusage.ru_utime),
+            stime: timeval_to_duration(rusage.ru_stime),
+            maxrss: (rusage.ru_maxrss as u64) * RSS_MULTIPLIER,
+        },
+        cpu_stat: None,
+        memory_io_stat: None,
+        oom_kills: 0,
+        timed_out: false,
+    }
 }
 
 fn wait4(pid: pid_t, options: i32) -> Result<Option<ExitResources>> {
@@ -367,21 +1377,405 @@ fn wait4(pid: pid_t, options: i32) -> Result<Option<ExitResources>> {
     } else {
         let rusage = unsafe { rusage.assume_init() };
 
-        Ok(Some(ExitResources {
-            status: ExitStatus::from_raw(status),
-            rusage: ResourceUsage {
-                utime: timeval_to_duration(rusage.ru_utime),
-                stime: timeval_to_duration(rusage.ru_stime),
-                maxrss: (rusage.ru_maxrss as u64) * RSS_MULTIPLIER,
-            },
-        }))
+        Ok(Some(exit_resources(status, &rusage)))
     }
 }
 
+/// Reaps `pid` without WNOHANG, for when it is known to have exited because its pidfd became
+/// readable. Blocks otherwise, so only call it from `spawn_blocking`.
+pub(crate) fn wait4_blocking(pid: pid_t) -> Result<ExitResources> {
+    wait4(pid, 0)?.ok_or_else(|| Error::other("wait4 returned no child"))
+}
+
+/// WNOHANG because a SIGCHLD or the polling interval doesn't mean this child exited: signals
+/// coalesce and SIGCHLD is for every child of the runner.
 impl Wait4 for Child {
     fn try_wait4(&mut self) -> Result<Option<ExitResources>> {
+        if let Some(pidfd) = &self.pidfd {
+            match waitid_pidfd(pidfd, libc::WNOHANG) {
+                // P_PIDFD is from Linux 5.4, a kernel between that and 5.2 only has the pid
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => self.pidfd = None,
+                res => return res,
+            }
+        }
         let pid = self.id() as i32;
 
         wait4(pid, libc::WNOHANG)
     }
 }
+
+/// Like [`wait4`], for the process `pidfd` refers to
+///
+/// The libc wrapper has no rusage argument, the raw syscall does.
+fn waitid_pidfd(pidfd: &PidFd, options: i32) -> Result<Option<ExitResources>> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut rusage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_waitid,
+            libc::P_PIDFD,
+            pidfd.as_raw_fd(),
+            &mut info as *mut libc::siginfo_t,
+            libc::WEXITED | options,
+            rusage.as_mut_ptr(),
+        )
+    };
+    if r < 0 {
+        return Err(Error::last_os_error());
+    }
+    // With WNOHANG and nothing to reap, the zeroed siginfo is left alone
+    if unsafe { info.si_pid() } == 0 {
+        return Ok(None);
+    }
+
+    // Back to the status word wait4 would have returned
+    let si_status = unsafe { info.si_status() };
+    let status = match info.si_code {
+        libc::CLD_EXITED => (si_status & 0xff) << 8,
+        libc::CLD_DUMPED => si_status | 0x80,
+        _ => si_status,
+    };
+    let rusage = unsafe { rusage.assume_init() };
+
+    Ok(Some(exit_resources(status, &rusage)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_maps_are_one_line() {
+        assert_eq!(single_id_map(Uid::from_raw(1000)), "0 1000 1\n");
+        assert_eq!(single_id_map(Gid::from_raw(0)), "0 0 1\n");
+    }
+
+    #[test]
+    fn write_id_maps() {
+        let dir = tempfile::tempdir().unwrap();
+        write_uid_map_at(dir.path(), Uid::from_raw(1234)).unwrap();
+        write_gid_map_at(dir.path(), Gid::from_raw(5678)).unwrap();
+
+        let read = |name| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("uid_map"), "0 1234 1\n");
+        assert_eq!(read("setgroups"), "deny");
+        assert_eq!(read("gid_map"), "0 5678 1\n");
+    }
+
+    #[test]
+    fn readonly_mounts_are_not_remounted() {
+        let dir = tempfile::tempdir().unwrap();
+        let fstab = dir.path().join("mounts");
+        fs::write(
+            &fstab,
+            "/dev/sda1 / ext4 rw,relatime 0 0\n\
+             /dev/sda2 /usr ext4 ro,relatime 0 0\n\
+             devtmpfs /dev devtmpfs rw,nosuid 0 0\n\
+             tmpfs /tmp tmpfs rw,nosuid,nodev 0 0\n\
+             proc /proc proc rw,relatime 0 0\n\
+             proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n",
+        )
+        .unwrap();
+
+        let mounts = MntEntOpener::new(&fstab).unwrap().list_all().unwrap();
+        let remounted: Vec<(String, String)> = mounts_to_remount(mounts)
+            .into_iter()
+            .map(|ent| (ent.mnt_dir, ent.mnt_opts))
+            .collect();
+        assert_eq!(
+            remounted,
+            [
+                ("/".into(), "rw,relatime".into()),
+                ("/tmp".into(), "rw,nosuid,nodev".into()),
+                ("/proc".into(), "rw,nosuid,nodev,noexec,relatime".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn writing_past_rlimit_fsize_raises_sigxfsz() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let mut cmd = process::Command::new("dd");
+        cmd.arg("if=/dev/zero")
+            .arg(format!("of={}", out.display()))
+            .args(["bs=1048576", "count=2"])
+            .stderr(process::Stdio::null());
+        unsafe {
+            cmd.pre_exec(|| {
+                set_rlimits(None, Some(1024 * 1024), CoreDumpPolicy::Disabled)?;
+                Ok(())
+            });
+        }
+        let status = cmd.status().unwrap();
+
+        assert_eq!(status.signal(), Some(libc::SIGXFSZ));
+        assert_eq!(fs::metadata(&out).unwrap().len(), 1024 * 1024);
+    }
+
+    /// Forks, returns true if the fork failed with EAGAIN, false if it succeeded
+    fn fork_hits_eagain() -> bool {
+        match unsafe { libc::fork() } {
+            0 => unsafe { libc::_exit(0) },
+            pid if pid > 0 => {
+                unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+                false
+            }
+            _ => Error::last_os_error().raw_os_error() == Some(libc::EAGAIN),
+        }
+    }
+
+    #[test]
+    fn rlimit_nproc_counts_pid1() {
+        // Root isn't held to RLIMIT_NPROC, so the stand-in for pid1 runs as a uid without any
+        // other processes to count
+        let unused = 1_999_999;
+        match unsafe { libc::fork() } {
+            0 => {
+                let pid1 = || -> nix::Result<bool> {
+                    if unistd::getuid().is_root() {
+                        unistd::setgid(Gid::from_raw(unused))?;
+                        unistd::setuid(Uid::from_raw(unused))?;
+                    }
+                    set_rlimits(Some(2), None, CoreDumpPolicy::Disabled)?;
+                    // The command, its own fork would be the third process
+                    let command = match unsafe { libc::fork() } {
+                        0 => unsafe { libc::_exit(if fork_hits_eagain() { 0 } else { 1 }) },
+                        pid if pid > 0 => pid,
+                        _ => return Ok(false),
+                    };
+                    let mut status = 0;
+                    unsafe { libc::waitpid(command, &mut status, 0) };
+                    Ok(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0)
+                };
+                let ok = pid1().unwrap_or(false);
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            pid => {
+                assert!(pid > 0, "fork failed: {}", Error::last_os_error());
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+    }
+
+    #[test]
+    fn negative_offsets() {
+        assert_eq!(negative_offset(Duration::new(5, 0)), "-5 0");
+        assert_eq!(
+            negative_offset(Duration::new(5, 250_000_000)),
+            "-6 750000000"
+        );
+        assert_eq!(negative_offset(Duration::ZERO), "0 0");
+    }
+
+    #[test]
+    fn timens_offsets_cancel_the_current_clocks() {
+        let before = clock_now(libc::CLOCK_MONOTONIC).unwrap();
+        let offsets = timens_offsets().unwrap();
+        let after = clock_now(libc::CLOCK_MONOTONIC).unwrap();
+
+        let lines: Vec<&str> = offsets.lines().collect();
+        assert_eq!(lines.len(), 2, "{offsets:?}");
+        let monotonic: Vec<i64> = lines[0]
+            .strip_prefix("monotonic ")
+            .unwrap()
+            .split(' ')
+            .map(|n| n.parse().unwrap())
+            .collect();
+        assert!(lines[1].starts_with("boottime -"), "{offsets:?}");
+
+        // secs + nanos is -now, for a monotonic reading taken in between
+        let offset_nanos = i128::from(monotonic[0]) * 1_000_000_000 + i128::from(monotonic[1]);
+        assert!(offset_nanos < 0);
+        assert!(-offset_nanos >= before.as_nanos() as i128);
+        assert!(-offset_nanos <= after.as_nanos() as i128);
+    }
+
+    /// Prints how long a namespace probe takes for each namespace and for the full set, a
+    /// measurement only. Run with
+    /// `cargo test --release probe_clone_timing -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn probe_clone_timing() {
+        const ROUNDS: u32 = 50;
+        // Every probe has a user namespace, as in probe_namespace_support()
+        let mut sets: Vec<(String, CloneFlags)> = DEFAULT_NAMESPACES
+            .iter()
+            .filter(|&ns| ns != CloneFlags::CLONE_NEWUSER)
+            .map(|ns| (format!("{ns:?}"), ns))
+            .collect();
+        sets.insert(0, ("user only".to_string(), CloneFlags::empty()));
+        sets.push(("DEFAULT_NAMESPACES".to_string(), DEFAULT_NAMESPACES));
+
+        for (name, flags) in sets {
+            let flags = flags | CloneFlags::CLONE_NEWUSER;
+            let start = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                assert!(probe_clone(flags, || 0).unwrap(), "{name}");
+            }
+            println!("{name}: {:?} per probe", start.elapsed() / ROUNDS);
+        }
+    }
+
+    /// Run by [`time_namespace_hides_host_clocks`] in a time namespace
+    #[test]
+    #[ignore]
+    fn print_monotonic_clock() {
+        if std::env::var_os("BB_RUNNER_PRINT_CLOCK").is_some() {
+            let now = clock_now(libc::CLOCK_MONOTONIC).unwrap();
+            println!("monotonic={}", now.as_nanos());
+        }
+    }
+
+    /// Monotonic clock of a process in a new time namespace, the test binary runs
+    /// [`print_monotonic_clock`] as a child of the shell that unshared it, like pid1 does
+    fn monotonic_in_time_namespace() -> Duration {
+        let offsets = timens_offsets().unwrap();
+        let mut cmd = process::Command::new("sh");
+        cmd.arg("-c")
+            .arg("\"$@\"; true")
+            .arg("sh")
+            .arg(std::env::current_exe().unwrap())
+            .args(["--exact", "child::tests::print_monotonic_clock"])
+            .args(["--ignored", "--nocapture", "--test-threads=1"])
+            .env("BB_RUNNER_PRINT_CLOCK", "1");
+        unsafe {
+            cmd.pre_exec(move || {
+                sched::unshare(CloneFlags::CLONE_NEWUSER)?;
+                unshare_time_namespace(&offsets)
+            });
+        }
+        let output = cmd.output().unwrap();
+        assert!(output.status.success(), "{output:?}");
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        // The harness prints the test's name on the same line
+        let (_, printed) = stdout
+            .split_once("monotonic=")
+            .unwrap_or_else(|| panic!("no clock printed: {stdout}"));
+        let nanos: String = printed.chars().take_while(char::is_ascii_digit).collect();
+        Duration::from_nanos(nanos.parse().unwrap())
+    }
+
+    #[test]
+    fn time_namespace_hides_host_clocks() {
+        if !probe_time_namespace() {
+            eprintln!("time namespaces are not supported, skipped");
+            return;
+        }
+        for _ in 0..2 {
+            let host_before = clock_now(libc::CLOCK_MONOTONIC).unwrap();
+            let job = monotonic_in_time_namespace();
+            let host_after = clock_now(libc::CLOCK_MONOTONIC).unwrap();
+
+            // The job's clock started near zero when the offsets were taken, so it is at most
+            // the time the spawn took
+            assert!(
+                job <= host_after - host_before,
+                "job {job:?} host {host_after:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_setup_leaves_no_process_behind() {
+        assert_eq!(children_of_this_thread(), "");
+        let mut command = Command::from(process::Command::new("/bin/true"));
+        // Longer than HOST_NAME_MAX, so sethostname fails in pid1
+        command.hostname(&"x".repeat(100));
+
+        match command.spawn().await {
+            Err(RunnerError::ChildSetup { code, message }) => {
+                assert_eq!(code, CHILD_SETUP_FAILED);
+                assert!(message.contains("os error 22"), "{message}");
+            }
+            Err(e) => panic!("expected a setup error, got {e}"),
+            Ok(_) => panic!("spawned with an invalid hostname"),
+        }
+        assert_eq!(children_of_this_thread(), "");
+    }
+
+    /// The NoNewPrivs line of /proc/self/status as seen by a command spawned with `no_new_privs`
+    async fn no_new_privs_of_command(set: bool) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("status");
+        let mut cmd = process::Command::new("grep");
+        cmd.args(["NoNewPrivs", "/proc/self/status"]);
+        let mut command = Command::from(cmd);
+        command
+            .no_new_privs(set)
+            .stdout(File::create(&path).unwrap());
+
+        let SpawnResult { child, .. } = command.spawn().await.unwrap();
+        let pid = pid_t::from(child.pid);
+        let exit = tokio::task::spawn_blocking(move || wait4_blocking(pid))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(exit.status.code(), Some(0));
+        fs::read_to_string(&path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn no_new_privs_is_set_in_the_command() {
+        assert_eq!(no_new_privs_of_command(true).await, "NoNewPrivs:\t1\n");
+    }
+
+    #[tokio::test]
+    async fn allow_new_privs_leaves_it_unset() {
+        assert_eq!(no_new_privs_of_command(false).await, "NoNewPrivs:\t0\n");
+    }
+
+    fn tv(tv_sec: i64, tv_usec: i64) -> timeval {
+        timeval {
+            tv_sec: tv_sec as libc::time_t,
+            tv_usec: tv_usec as libc::suseconds_t,
+        }
+    }
+
+    #[test]
+    fn timeval_conversion() {
+        assert_eq!(timeval_to_duration(tv(0, 0)), Duration::ZERO);
+        assert_eq!(
+            timeval_to_duration(tv(3, 250_000)),
+            Duration::from_millis(3250)
+        );
+        assert_eq!(
+            timeval_to_duration(tv(i32::MAX.into(), 0)),
+            Duration::from_secs(i32::MAX as u64)
+        );
+        // Out of range microseconds are carried into the seconds
+        assert_eq!(
+            timeval_to_duration(tv(1, 2_500_000)),
+            Duration::from_millis(3500)
+        );
+    }
+
+    #[test]
+    fn timeval_extremes_do_not_panic() {
+        let max = timeval_to_duration(tv(i64::MAX, 999_999));
+        assert_eq!(max.as_secs(), i64::MAX as u64);
+        timeval_to_duration(tv(i64::MAX, i64::MAX));
+    }
+
+    #[test]
+    fn negative_timeval_fields_clamp_to_zero() {
+        assert_eq!(timeval_to_duration(tv(-1, 0)), Duration::ZERO);
+        assert_eq!(timeval_to_duration(tv(i64::MIN, -1)), Duration::ZERO);
+        assert_eq!(timeval_to_duration(tv(2, -5)), Duration::from_secs(2));
+        assert_eq!(timeval_to_duration(tv(-2, 5)), Duration::from_micros(5));
+    }
+
+    #[test]
+    fn gid_map_needs_setgroups_denied_first() {
+        let dir = tempfile::tempdir().unwrap();
+        // Can't be opened as a file, so denying setgroups fails
+        fs::create_dir(dir.path().join("setgroups")).unwrap();
+
+        assert!(write_gid_map_at(dir.path(), Gid::from_raw(5678)).is_err());
+        assert!(!dir.path().join("gid_map").exists());
+    }
+}
@@ -0,0 +1,1174 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+
+use nix::sys::signal::Signal;
+
+use crate::capabilities::{capability_number, DEFAULT_DROP_LIST};
+use crate::json::{self, Value};
+use crate::seccomp::SeccompProfile;
+
+/// Runner configuration, loaded from a JSON file
+///
+/// Every field has a default, so a missing file section or key just keeps the default. Unknown
+/// keys are rejected to catch typos, every problem in the file is reported at once.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Configuration {
+    pub server: ServerConfig,
+    pub sandbox: SandboxConfig,
+    pub cgroup: CgroupConfig,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ServerConfig {
+    /// Also serve on this TCP address, besides the `runner` socket in the working directory.
+    ///
+    /// There is no authentication, anyone who can connect runs commands as the runner user.
+    /// Only bind it to loopback or a network just the workers are on.
+    pub tcp_listen_address: Option<SocketAddr>,
+    /// Set SO_REUSEPORT on the TCP listener (Linux 3.9 or later), so the runner replacing this
+    /// one in a rolling restart can bind the same port while this one drains. The kernel spreads
+    /// new connections over both until this one exits. Off by default.
+    pub tcp_reuse_port: bool,
+    /// Append a JSON line describing every finished job to this file
+    pub audit_log_path: Option<PathBuf>,
+    /// Rotate the audit log to `<path>.1` once it grows beyond this size
+    pub audit_log_max_size_bytes: Option<u64>,
+    /// Number of job slots, defaults to the number of CPUs. More slots than CPUs share the CPUs
+    /// round-robin, fewer throttle the runner below the CPU count.
+    pub max_concurrent_jobs: Option<u32>,
+    /// First job slot number. Slots name the job cgroups, runners sharing a cgroup root need
+    /// distinct ranges of them, e.g. 0 and 4 for two runners of 4 slots each.
+    pub cpu_id_offset: u32,
+    /// Largest request accepted, tonic defaults to 4 MiB.
+    ///
+    /// Outputs are normally left in the build directory rather than inlined in the RunResponse,
+    /// streaming them would be preferable to raising these limits far.
+    pub grpc_max_decode_message_bytes: Option<usize>,
+    /// Largest response sent, unlimited by default in tonic
+    pub grpc_max_encode_message_bytes: Option<usize>,
+    /// Serve gRPC reflection, which grpcurl needs to work without the protos at hand.
+    ///
+    /// It is not needed for health checking, CheckReadiness is a plain Runner RPC.
+    pub enable_grpc_reflection: bool,
+    /// Services not to describe over reflection, the reflection service itself included
+    pub grpc_reflection_deny_services: Vec<String>,
+    /// Jobs running longer than this get SIGTERM, no limit by default. A request's own `timeout`
+    /// can only make it shorter.
+    pub job_timeout_secs: Option<u64>,
+    /// Delay between SIGTERM and SIGKILL, for timed out or cancelled jobs (default 10)
+    pub kill_grace_period_secs: u64,
+    /// How long after SIGKILL the job may take to be reaped before it is declared unresponsive
+    /// (default 30)
+    pub cleanup_timeout_secs: u64,
+    /// How long SIGTERM waits for the running jobs to finish before the runner exits anyway
+    /// (default 60)
+    pub shutdown_drain_timeout_secs: u64,
+    /// Sample the `memory.current` of every running job this often, reported as job events
+    /// (logged at debug level). Off by default.
+    pub memory_sample_interval_secs: Option<u64>,
+    pub log_format: LogFormat,
+}
+
+/// How log lines are written to stderr
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tcp_listen_address: None,
+            tcp_reuse_port: false,
+            audit_log_path: None,
+            audit_log_max_size_bytes: None,
+            max_concurrent_jobs: None,
+            cpu_id_offset: 0,
+            grpc_max_decode_message_bytes: None,
+            grpc_max_encode_message_bytes: None,
+            enable_grpc_reflection: true,
+            grpc_reflection_deny_services: Vec::new(),
+            job_timeout_secs: None,
+            kill_grace_period_secs: 10,
+            cleanup_timeout_secs: 30,
+            shutdown_drain_timeout_secs: 60,
+            memory_sample_interval_secs: None,
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SandboxConfig {
+    /// Trusted command run outside the sandbox before every job, with `BB_BUILD_DIR`,
+    /// `BB_INPUT_ROOT` and `BB_WORKING_DIR` set. The job is not started if it fails.
+    pub pre_spawn_hook: Option<Vec<String>>,
+    /// The pre-spawn hook is killed, and the job not started, if it runs longer than this
+    pub pre_spawn_hook_timeout_secs: u64,
+    /// Trusted command run outside the sandbox after every job, with the exit code (or
+    /// `BB_SIGNAL` if the command was killed), rusage and output paths in `BB_*` variables. It
+    /// can't affect the job's result.
+    pub post_exec_hook: Option<Vec<String>>,
+    /// The post-exec hook is killed if it runs longer than this
+    pub post_exec_hook_timeout_secs: u64,
+    pub network_policy: NetworkPolicy,
+    /// Remount every mount except `/dev` read-only in the job's mount namespace.
+    /// Turning it off keeps the host's mount flags, e.g. when the runner already runs in a
+    /// container with a read-only root.
+    pub remount_as_readonly: bool,
+    /// Remount `/sys` read-only, nosuid, noexec and nodev in the job's mount namespace
+    pub remount_sysfs: bool,
+    /// Give the command its own time namespace, so the monotonic and boottime clocks don't
+    /// reveal the host's uptime. Needs Linux 5.6 or later.
+    pub isolate_time_namespace: bool,
+    /// Give the job its own cgroup namespace even when it is not put in a cgroup, so it sees
+    /// its own cgroup as the root instead of the host's hierarchy
+    pub isolate_cgroup_namespace: bool,
+    /// Give the job its own UTS namespace, so `uname()` doesn't return the host's names
+    pub isolate_uts_namespace: bool,
+    /// Hostname of every job, e.g. to tell from build logs which runner ran it
+    pub sandbox_hostname: String,
+    /// NIS domain name of every job, `(none)` is what the kernel reports while none is set
+    pub sandbox_domainname: String,
+    /// Host paths made visible at another path in the job's mount namespace, e.g. toolchains
+    /// that are not part of the input root
+    pub additional_bind_mounts: Vec<BindMountConfig>,
+    /// Values written to `/proc/sys/<key>` in the job's namespaces, keys use `/` separators, e.g.
+    /// `net/core/somaxconn`. Only sysctls namespaced by UTS, IPC or NET can be changed.
+    pub sysctl_overrides: HashMap<String, String>,
+    /// RLIMIT_NPROC of the command, on top of the cgroup's `pids.max`.
+    ///
+    /// The rlimit counts every process of the uid, and inside the job's user namespace every
+    /// process runs as uid 0, so it caps the whole job much like `pids.max` caps the cgroup.
+    /// Either one stopping a fork bomb is enough, having both keeps one if the other is missing.
+    pub rlimit_nproc: Option<u32>,
+    /// RLIMIT_FSIZE of the command in MiB, writing a file beyond it raises SIGXFSZ, which kills
+    /// the writer
+    pub rlimit_fsize_mb: Option<u32>,
+    /// RLIMIT_CORE of the command, by default a crashing test leaves no core file in the build
+    /// directory
+    pub rlimit_core: CoreDumpPolicy,
+    /// Let the command gain privileges through setuid binaries and file capabilities. Off by
+    /// default, pid1 sets no_new_privs for the command and everything it runs. A seccomp filter
+    /// always sets it.
+    pub allow_new_privs: bool,
+    /// Run the command under a seccomp filter, every syscall not in `seccomp_allowlist` fails
+    /// with EPERM. pid1 and the runner's own setup are not filtered.
+    pub seccomp_enabled: bool,
+    /// Syscall names the command may use, `None` allows what typical build tools need
+    pub seccomp_allowlist: Option<Vec<String>>,
+    /// Capabilities, like `CAP_SYS_ADMIN`, removed from the command's bounding set. Inside its
+    /// user namespace the command is root and would otherwise hold every one of them there.
+    pub capability_drop_list: Vec<String>,
+    /// Drop every capability except the few that build steps running as root commonly need,
+    /// `capability_drop_list` is then ignored
+    pub capability_drop_all: bool,
+    /// Sent to the job when the runner dies without cleaning up, `None` leaves it running.
+    ///
+    /// SIGKILL (the default) is sure to stop everything. Anything else gives the job's processes
+    /// a chance to clean up, but a job ignoring it keeps running with nobody to reap it. Jobs the
+    /// runner cancels itself are always stopped with SIGTERM and then SIGKILL.
+    pub pdeathsig: Option<Signal>,
+    /// Warn, or act per `free_disk_policy`, while a job runs with less free space than this in
+    /// the build directory
+    pub min_free_disk_gb: Option<u32>,
+    pub free_disk_policy: LowDiskPolicy,
+    /// Working directory of jobs whose request leaves it empty, relative to the input root.
+    /// Without it they run in the input root itself.
+    pub default_working_directory: Option<String>,
+    /// Fail a job whose working directory doesn't exist before spawning it, instead of when
+    /// pid1 fails to chdir into it
+    pub working_directory_must_exist: bool,
+    /// Mount a tmpfs of this size on the job's home, so caches like Maven's or npm's can't fill
+    /// the build directory. At least 1, tools still write small files like `.gitconfig` there.
+    pub home_size_limit_mb: Option<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BindMountConfig {
+    pub host_path: PathBuf,
+    /// Must already exist, the rest of the filesystem is read-only by the time it is mounted
+    pub sandbox_path: PathBuf,
+    pub readonly: bool,
+}
+
+/// Mounted by pid1 itself, binding over them would break the sandbox
+const RESERVED_SANDBOX_PATHS: [&str; 3] = ["/proc", "/sys", "/dev"];
+
+/// What to do with a running job when the build directory is low on space
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LowDiskPolicy {
+    Warn,
+    /// Stop the job like a cancelled one, it would most likely fail with ENOSPC anyway
+    Kill,
+}
+
+/// Network access of jobs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum NetworkPolicy {
+    /// Own network namespace with only `lo` up, jobs can talk to themselves but nothing else
+    LoopbackOnly,
+    /// Own network namespace with every interface down, not even loopback servers work
+    None,
+    /// The host's network namespace. Jobs can reach anything the runner can, including
+    /// services only listening on the host's loopback, and abstract unix sockets.
+    FullNetwork,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            pre_spawn_hook: None,
+            pre_spawn_hook_timeout_secs: 30,
+            post_exec_hook: None,
+            post_exec_hook_timeout_secs: 30,
+            network_policy: NetworkPolicy::LoopbackOnly,
+            remount_as_readonly: true,
+            remount_sysfs: true,
+            isolate_time_namespace: false,
+            isolate_cgroup_namespace: true,
+            isolate_uts_namespace: true,
+            sandbox_hostname: "localhost".to_string(),
+            sandbox_domainname: "(none)".to_string(),
+            additional_bind_mounts: Vec::new(),
+            sysctl_overrides: HashMap::new(),
+            rlimit_nproc: None,
+            rlimit_fsize_mb: None,
+            rlimit_core: CoreDumpPolicy::Disabled,
+            allow_new_privs: false,
+            seccomp_enabled: false,
+            seccomp_allowlist: None,
+            capability_drop_list: DEFAULT_DROP_LIST.iter().map(|c| c.to_string()).collect(),
+            capability_drop_all: false,
+            pdeathsig: Some(Signal::SIGKILL),
+            min_free_disk_gb: Some(1),
+            free_disk_policy: LowDiskPolicy::Warn,
+            default_working_directory: None,
+            working_directory_must_exist: true,
+            home_size_limit_mb: None,
+        }
+    }
+}
+
+/// Size of the core files the job's processes may write
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CoreDumpPolicy {
+    /// RLIMIT_CORE of 0
+    Disabled,
+    /// Raise RLIMIT_CORE to the runner's hard limit
+    Unlimited,
+    /// Up to this many bytes per core file
+    MaxBytes(u64),
+}
+
+/// How much swap a job may use on top of its memory limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SwapPolicy {
+    /// No swap at all
+    Disabled,
+    /// Leave the swap limit at the kernel default
+    Unlimited,
+    /// Up to this many bytes of swap
+    MaxBytes(u64),
+    /// As much swap as the memory limit
+    SameAsMemory,
+}
+
+/// When a job's cgroup is removed after it exits, a kept cgroup is renamed to
+/// `job<slot>.<unix millis>` and left for inspection until it is removed by hand
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CgroupCleanupPolicy {
+    Always,
+    /// Keep the cgroups of failed jobs
+    OnSuccess,
+    /// Keep the cgroups of successful jobs
+    OnFailure,
+    Never,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CgroupConfig {
+    /// Watch the pressure stall information of the bb_runner cgroup and report not ready while
+    /// it is over `psi_threshold`.
+    pub psi_monitoring: bool,
+    /// Limit for the `some avg10` percentage of any of cpu, memory or io pressure.
+    pub psi_threshold: f64,
+    pub swap_policy: SwapPolicy,
+    pub cleanup_policy: CgroupCleanupPolicy,
+    /// RLIMIT_CPU of the job's processes, each one is killed once it used this much CPU time
+    pub cpu_time_limit_secs: Option<u32>,
+    /// Set `memory.oom.group` on cgroup v2, so an OOM kill takes the whole job instead of one
+    /// process. When off, the runner doesn't stop a job on an OOM kill either, the job's
+    /// processes may handle a killed child, and the count is only reported when it ends.
+    pub oom_group: bool,
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        Self {
+            psi_monitoring: false,
+            psi_threshold: 20.0,
+            swap_policy: SwapPolicy::Disabled,
+            cleanup_policy: CgroupCleanupPolicy::Always,
+            cpu_time_limit_secs: None,
+            oom_group: true,
+        }
+    }
+}
+
+/// Why the configuration could not be loaded
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    ReadFailed {
+        path: PathBuf,
+        source: io::Error,
+    },
+    JsonParseFailed {
+        path: PathBuf,
+        source: json::ParseError,
+    },
+    /// Every invalid or unknown key, as `section.key: problem`
+    ValidationFailed(Vec<String>),
+    /// More than one file could not be loaded
+    Multiple(Vec<ConfigError>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ReadFailed { path, source } => {
+                write!(f, "failed to read {}: {source}", path.display())
+            }
+            ConfigError::JsonParseFailed { path, source } => {
+                write!(f, "failed to parse {}: {source}", path.display())
+            }
+            ConfigError::ValidationFailed(errors) => write!(f, "{}", errors.join(", ")),
+            ConfigError::Multiple(errors) => {
+                let msgs: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "{}", msgs.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::ReadFailed { source, .. } => Some(source),
+            ConfigError::JsonParseFailed { source, .. } => Some(source),
+            ConfigError::ValidationFailed(_) | ConfigError::Multiple(_) => None,
+        }
+    }
+}
+
+impl Configuration {
+    /// Later files override earlier ones: objects are merged key by key, anything else is
+    /// replaced. Every file is read before giving up, so all broken files are reported at once.
+    pub fn new(paths: &[&Path]) -> Result<Configuration, ConfigError> {
+        let mut value = Value::Object(Vec::new());
+        let mut errors = Vec::new();
+        for path in paths {
+            match load_file(path) {
+                Ok(v) => value.merge(v),
+                Err(e) => errors.push(e),
+            }
+        }
+        match errors.len() {
+            0 => {}
+            1 => return Err(errors.remove(0)),
+            _ => return Err(ConfigError::Multiple(errors)),
+        }
+        Self::from_json(&value)
+    }
+
+    /// The configuration in the merged document `value`, defaults for what it doesn't set
+    pub fn from_json(value: &Value) -> Result<Configuration, ConfigError> {
+        let mut errors = Vec::new();
+        let mut config = Configuration::default();
+        for (key, val) in sections(value, "configuration", &mut errors) {
+            match key.as_str() {
+                "server" => config.server = ServerConfig::from_json(val, &mut errors),
+                "sandbox" => config.sandbox = SandboxConfig::from_json(val, &mut errors),
+                "cgroup" => config.cgroup = CgroupConfig::from_json(val, &mut errors),
+                _ => errors.push(format!("{key}: unknown section")),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(ConfigError::ValidationFailed(errors));
+        }
+        Ok(config)
+    }
+
+    /// Every effective value as `section.key` and a readable value, defaults included
+    pub fn describe(&self) -> Vec<(String, String)> {
+        fn opt<T: fmt::Display>(val: &Option<T>) -> String {
+            val.as_ref().map_or("unset".to_string(), T::to_string)
+        }
+        fn argv(val: &Option<Vec<String>>) -> String {
+            val.as_ref().map_or("unset".to_string(), |a| a.join(" "))
+        }
+        let (server, sandbox, cgroup) = (&self.server, &self.sandbox, &self.cgroup);
+
+        let mut sysctls: Vec<String> = sandbox
+            .sysctl_overrides
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect();
+        sysctls.sort();
+        let bind_mounts: Vec<String> = sandbox
+            .additional_bind_mounts
+            .iter()
+            .map(|m| {
+                let mode = if m.readonly { "ro" } else { "rw" };
+                format!(
+                    "{}:{}:{mode}",
+                    m.host_path.display(),
+                    m.sandbox_path.display()
+                )
+            })
+            .collect();
+
+        let entries = [
+            ("server.tcp_listen_address", opt(&server.tcp_listen_address)),
+            ("server.tcp_reuse_port", server.tcp_reuse_port.to_string()),
+            (
+                "server.audit_log_path",
+                opt(&server.audit_log_path.as_ref().map(|p| p.display())),
+            ),
+            (
+                "server.audit_log_max_size_bytes",
+                opt(&server.audit_log_max_size_bytes),
+            ),
+            (
+                "server.max_concurrent_jobs",
+                opt(&server.max_concurrent_jobs),
+            ),
+            ("server.cpu_id_offset", server.cpu_id_offset.to_string()),
+            (
+                "server.grpc_max_decode_message_bytes",
+                opt(&server.grpc_max_decode_message_bytes),
+            ),
+            (
+                "server.grpc_max_encode_message_bytes",
+                opt(&server.grpc_max_encode_message_bytes),
+            ),
+            (
+                "server.enable_grpc_reflection",
+                server.enable_grpc_reflection.to_string(),
+            ),
+            (
+                "server.grpc_reflection_deny_services",
+                server.grpc_reflection_deny_services.join(","),
+            ),
+            ("server.job_timeout_secs", opt(&server.job_timeout_secs)),
+            (
+                "server.kill_grace_period_secs",
+                server.kill_grace_period_secs.to_string(),
+            ),
+            (
+                "server.cleanup_timeout_secs",
+                server.cleanup_timeout_secs.to_string(),
+            ),
+            (
+                "server.shutdown_drain_timeout_secs",
+                server.shutdown_drain_timeout_secs.to_string(),
+            ),
+            (
+                "server.memory_sample_interval_secs",
+                opt(&server.memory_sample_interval_secs),
+            ),
+            ("server.log_format", format!("{:?}", server.log_format)),
+            ("sandbox.pre_spawn_hook", argv(&sandbox.pre_spawn_hook)),
+            (
+                "sandbox.pre_spawn_hook_timeout_secs",
+                sandbox.pre_spawn_hook_timeout_secs.to_string(),
+            ),
+            ("sandbox.post_exec_hook", argv(&sandbox.post_exec_hook)),
+            (
+                "sandbox.post_exec_hook_timeout_secs",
+                sandbox.post_exec_hook_timeout_secs.to_string(),
+            ),
+            (
+                "sandbox.network_policy",
+                format!("{:?}", sandbox.network_policy),
+            ),
+            (
+                "sandbox.remount_as_readonly",
+                sandbox.remount_as_readonly.to_string(),
+            ),
+            ("sandbox.remount_sysfs", sandbox.remount_sysfs.to_string()),
+            (
+                "sandbox.isolate_time_namespace",
+                sandbox.isolate_time_namespace.to_string(),
+            ),
+            (
+                "sandbox.isolate_cgroup_namespace",
+                sandbox.isolate_cgroup_namespace.to_string(),
+            ),
+            (
+                "sandbox.isolate_uts_namespace",
+                sandbox.isolate_uts_namespace.to_string(),
+            ),
+            ("sandbox.sandbox_hostname", sandbox.sandbox_hostname.clone()),
+            (
+                "sandbox.sandbox_domainname",
+                sandbox.sandbox_domainname.clone(),
+            ),
+            ("sandbox.additional_bind_mounts", bind_mounts.join(",")),
+            ("sandbox.sysctl_overrides", sysctls.join(",")),
+            ("sandbox.rlimit_nproc", opt(&sandbox.rlimit_nproc)),
+            ("sandbox.rlimit_fsize_mb", opt(&sandbox.rlimit_fsize_mb)),
+            ("sandbox.rlimit_core", format!("{:?}", sandbox.rlimit_core)),
+            (
+                "sandbox.allow_new_privs",
+                sandbox.allow_new_privs.to_string(),
+            ),
+            (
+                "sandbox.seccomp_enabled",
+                sandbox.seccomp_enabled.to_string(),
+            ),
+            (
+                "sandbox.capability_drop_list",
+                sandbox.capability_drop_list.join(","),
+            ),
+            (
+                "sandbox.capability_drop_all",
+                sandbox.capability_drop_all.to_string(),
+            ),
+            (
+                "sandbox.seccomp_allowlist",
+                opt(&sandbox.seccomp_allowlist.as_ref().map(|l| l.join(","))),
+            ),
+            ("sandbox.pdeathsig", opt(&sandbox.pdeathsig)),
+            ("sandbox.min_free_disk_gb", opt(&sandbox.min_free_disk_gb)),
+            (
+                "sandbox.free_disk_policy",
+                format!("{:?}", sandbox.free_disk_policy),
+            ),
+            (
+                "sandbox.default_working_directory",
+                opt(&sandbox.default_working_directory),
+            ),
+            (
+                "sandbox.working_directory_must_exist",
+                sandbox.working_directory_must_exist.to_string(),
+            ),
+            (
+                "sandbox.home_size_limit_mb",
+                opt(&sandbox.home_size_limit_mb),
+            ),
+            ("cgroup.psi_monitoring", cgroup.psi_monitoring.to_string()),
+            ("cgroup.psi_threshold", cgroup.psi_threshold.to_string()),
+            ("cgroup.swap_policy", format!("{:?}", cgroup.swap_policy)),
+            (
+                "cgroup.cleanup_policy",
+                format!("{:?}", cgroup.cleanup_policy),
+            ),
+            (
+                "cgroup.cpu_time_limit_secs",
+                opt(&cgroup.cpu_time_limit_secs),
+            ),
+            ("cgroup.oom_group", cgroup.oom_group.to_string()),
+        ];
+        entries
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect()
+    }
+}
+
+fn load_file(path: &Path) -> Result<Value, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::ReadFailed {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    json::parse(&contents).map_err(|source| ConfigError::JsonParseFailed {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// The keys of an object, anything else is reported as invalid and has no keys
+fn sections<'a>(value: &'a Value, name: &str, errors: &mut Vec<String>) -> &'a [(String, Value)] {
+    value.as_object().unwrap_or_else(|| {
+        errors.push(format!("{name}: expected an object, got {value}"));
+        &[]
+    })
+}
+
+/// Stores a successfully converted value, `None` means it was invalid
+fn set<T>(slot: &mut T, val: Option<T>) -> Option<()> {
+    *slot = val?;
+    Some(())
+}
+
+/// Parses `section.key` with `parse`, an invalid value or unknown key is recorded in `errors`
+fn parse_keys<F>(value: &Value, section: &str, errors: &mut Vec<String>, mut parse: F)
+where
+    F: FnMut(&str, &Value) -> Option<Option<()>>,
+{
+    for (key, val) in sections(value, section, errors) {
+        match parse(key, val) {
+            Some(Some(())) => {}
+            Some(None) => errors.push(format!("{section}.{key}: invalid value {val}")),
+            None => errors.push(format!("{section}.{key}: unknown key")),
+        }
+    }
+}
+
+fn as_u32(val: &Value) -> Option<u32> {
+    u32::try_from(val.as_u64()?).ok()
+}
+
+fn as_usize(val: &Value) -> Option<usize> {
+    usize::try_from(val.as_u64()?).ok()
+}
+
+impl ServerConfig {
+    fn from_json(value: &Value, errors: &mut Vec<String>) -> Self {
+        let mut config = Self::default();
+        parse_keys(value, "server", errors, |key, val| {
+            Some(match key {
+                "tcp_listen_address" => set(
+                    &mut config.tcp_listen_address,
+                    val.as_str().and_then(|a| a.parse().ok()).map(Some),
+                ),
+                "tcp_reuse_port" => set(&mut config.tcp_reuse_port, val.as_bool()),
+                "audit_log_path" => set(
+                    &mut config.audit_log_path,
+                    val.as_str().map(|p| Some(p.into())),
+                ),
+                "audit_log_max_size_bytes" => {
+                    set(&mut config.audit_log_max_size_bytes, val.as_u64().map(Some))
+                }
+                "max_concurrent_jobs" => set(
+                    &mut config.max_concurrent_jobs,
+                    as_u32(val).filter(|&n| n > 0).map(Some),
+                ),
+                "cpu_id_offset" => set(&mut config.cpu_id_offset, as_u32(val)),
+                "grpc_max_decode_message_bytes" => set(
+                    &mut config.grpc_max_decode_message_bytes,
+                    as_usize(val).map(Some),
+                ),
+                "grpc_max_encode_message_bytes" => set(
+                    &mut config.grpc_max_encode_message_bytes,
+                    as_usize(val).map(Some),
+                ),
+                "enable_grpc_reflection" => set(&mut config.enable_grpc_reflection, val.as_bool()),
+                "grpc_reflection_deny_services" => set(
+                    &mut config.grpc_reflection_deny_services,
+                    strings_from_json(val),
+                ),
+                "job_timeout_secs" => set(&mut config.job_timeout_secs, val.as_u64().map(Some)),
+                "kill_grace_period_secs" => set(&mut config.kill_grace_period_secs, val.as_u64()),
+                "cleanup_timeout_secs" => set(&mut config.cleanup_timeout_secs, val.as_u64()),
+                "shutdown_drain_timeout_secs" => {
+                    set(&mut config.shutdown_drain_timeout_secs, val.as_u64())
+                }
+                "memory_sample_interval_secs" => set(
+                    &mut config.memory_sample_interval_secs,
+                    val.as_u64().filter(|&n| n > 0).map(Some),
+                ),
+                "log_format" => set(&mut config.log_format, LogFormat::from_json(val)),
+                _ => return None,
+            })
+        });
+
+        config
+    }
+}
+
+impl SandboxConfig {
+    fn from_json(value: &Value, errors: &mut Vec<String>) -> Self {
+        let mut config = Self::default();
+        parse_keys(value, "sandbox", errors, |key, val| {
+            Some(match key {
+                "pre_spawn_hook" => set(&mut config.pre_spawn_hook, argv_from_json(val).map(Some)),
+                "pre_spawn_hook_timeout_secs" => {
+                    set(&mut config.pre_spawn_hook_timeout_secs, val.as_u64())
+                }
+                "post_exec_hook" => set(&mut config.post_exec_hook, argv_from_json(val).map(Some)),
+                "post_exec_hook_timeout_secs" => {
+                    set(&mut config.post_exec_hook_timeout_secs, val.as_u64())
+                }
+                "network_policy" => set(&mut config.network_policy, NetworkPolicy::from_json(val)),
+                "remount_as_readonly" => set(&mut config.remount_as_readonly, val.as_bool()),
+                "remount_sysfs" => set(&mut config.remount_sysfs, val.as_bool()),
+                "isolate_time_namespace" => set(&mut config.isolate_time_namespace, val.as_bool()),
+                "rlimit_nproc" => set(
+                    &mut config.rlimit_nproc,
+                    as_u32(val).filter(|&n| n > 0).map(Some),
+                ),
+                "rlimit_fsize_mb" => set(
+                    &mut config.rlimit_fsize_mb,
+                    as_u32(val).filter(|&n| n > 0).map(Some),
+                ),
+                "rlimit_core" => set(&mut config.rlimit_core, CoreDumpPolicy::from_json(val)),
+                "allow_new_privs" => set(&mut config.allow_new_privs, val.as_bool()),
+                "seccomp_enabled" => set(&mut config.seccomp_enabled, val.as_bool()),
+                "capability_drop_list" => {
+                    set(&mut config.capability_drop_list, strings_from_json(val))
+                }
+                "capability_drop_all" => set(&mut config.capability_drop_all, val.as_bool()),
+                "seccomp_allowlist" => set(
+                    &mut config.seccomp_allowlist,
+                    match val {
+                        Value::Null => Some(None),
+                        _ => strings_from_json(val).map(Some),
+                    },
+                ),
+                "pdeathsig" => set(
+                    &mut config.pdeathsig,
+                    match val {
+                        Value::Null => Some(None),
+                        _ => val.as_str().and_then(|s| s.parse().ok()).map(Some),
+                    },
+                ),
+                "sysctl_overrides" => set(&mut config.sysctl_overrides, sysctls_from_json(val)),
+                // At most HOST_NAME_MAX bytes
+                "sandbox_hostname" => set(
+                    &mut config.sandbox_hostname,
+                    val.as_str()
+                        .filter(|h| !h.is_empty() && h.len() <= 64)
+                        .map(String::from),
+                ),
+                // At most 64 bytes too, but may be empty
+                "sandbox_domainname" => set(
+                    &mut config.sandbox_domainname,
+                    val.as_str().filter(|d| d.len() <= 64).map(String::from),
+                ),
+                "isolate_uts_namespace" => set(&mut config.isolate_uts_namespace, val.as_bool()),
+                "isolate_cgroup_namespace" => {
+                    set(&mut config.isolate_cgroup_namespace, val.as_bool())
+                }
+                "additional_bind_mounts" => set(
+                    &mut config.additional_bind_mounts,
+                    val.as_array()
+                        .and_then(|mounts| mounts.iter().map(BindMountConfig::from_json).collect()),
+                ),
+                "min_free_disk_gb" => set(
+                    &mut config.min_free_disk_gb,
+                    match val {
+                        Value::Null => Some(None),
+                        _ => as_u32(val).map(Some),
+                    },
+                ),
+                "free_disk_policy" => {
+                    set(&mut config.free_disk_policy, LowDiskPolicy::from_json(val))
+                }
+                "default_working_directory" => set(
+                    &mut config.default_working_directory,
+                    val.as_str().map(|d| Some(d.to_string())),
+                ),
+                "working_directory_must_exist" => {
+                    set(&mut config.working_directory_must_exist, val.as_bool())
+                }
+                "home_size_limit_mb" => set(
+                    &mut config.home_size_limit_mb,
+                    match val {
+                        Value::Null => Some(None),
+                        _ => as_u32(val).map(Some),
+                    },
+                ),
+                _ => return None,
+            })
+        });
+
+        for key in config.sysctl_overrides.keys() {
+            let valid =
+                !key.is_empty() && !key.contains('.') && key.split('/').all(|c| !c.is_empty());
+            if !valid {
+                errors.push(format!(
+                    "sandbox.sysctl_overrides: {key:?} must be a relative path like net/core/somaxconn"
+                ));
+            }
+        }
+        for mount in &config.additional_bind_mounts {
+            errors.extend(mount.validate());
+        }
+        for name in &config.capability_drop_list {
+            if capability_number(name).is_none() {
+                errors.push(format!(
+                    "sandbox.capability_drop_list: unknown capability {name:?}"
+                ));
+            }
+        }
+        if let Some(names) = &config.seccomp_allowlist {
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            if let Err(e) = SeccompProfile::allowlist(&names) {
+                errors.push(format!("sandbox.seccomp_allowlist: {e}"));
+            }
+        }
+        if config.home_size_limit_mb == Some(0) {
+            errors.push("sandbox.home_size_limit_mb: must be at least 1".to_string());
+        }
+        if let Some(dir) = &config.default_working_directory {
+            let escapes = Path::new(dir)
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+            if escapes {
+                errors.push(format!(
+                    "sandbox.default_working_directory: {dir:?} must be a relative path without '..'"
+                ));
+            }
+        }
+
+        config
+    }
+}
+
+impl BindMountConfig {
+    fn from_json(value: &Value) -> Option<Self> {
+        let mut mount = Self {
+            host_path: PathBuf::new(),
+            sandbox_path: PathBuf::new(),
+            readonly: true,
+        };
+        for (key, val) in value.as_object()? {
+            match key.as_str() {
+                "host_path" => mount.host_path = val.as_str()?.into(),
+                "sandbox_path" => mount.sandbox_path = val.as_str()?.into(),
+                "readonly" => mount.readonly = val.as_bool()?,
+                _ => return None,
+            }
+        }
+
+        Some(mount)
+    }
+
+    fn validate(&self) -> Option<String> {
+        let name = "sandbox.additional_bind_mounts";
+        if !self.host_path.is_absolute() || !self.host_path.exists() {
+            return Some(format!(
+                "{name}: host_path {:?} does not exist",
+                self.host_path
+            ));
+        }
+        let reserved = RESERVED_SANDBOX_PATHS
+            .iter()
+            .any(|p| self.sandbox_path.starts_with(p));
+        if !self.sandbox_path.is_absolute() || self.sandbox_path == Path::new("/") || reserved {
+            return Some(format!(
+                "{name}: sandbox_path {:?} must be absolute and not /, /proc, /sys or /dev",
+                self.sandbox_path
+            ));
+        }
+
+        None
+    }
+}
+
+/// An object of string values
+fn sysctls_from_json(value: &Value) -> Option<HashMap<String, String>> {
+    value
+        .as_object()?
+        .iter()
+        .map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+        .collect()
+}
+
+/// An array of strings
+fn strings_from_json(value: &Value) -> Option<Vec<String>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|a| a.as_str().map(String::from))
+        .collect()
+}
+
+/// A command line, which must at least have the program
+fn argv_from_json(value: &Value) -> Option<Vec<String>> {
+    strings_from_json(value).filter(|argv| !argv.is_empty())
+}
+
+impl CgroupConfig {
+    fn from_json(value: &Value, errors: &mut Vec<String>) -> Self {
+        let mut config = Self::default();
+        parse_keys(value, "cgroup", errors, |key, val| {
+            Some(match key {
+                "psi_monitoring" => set(&mut config.psi_monitoring, val.as_bool()),
+                "psi_threshold" => set(&mut config.psi_threshold, val.as_f64()),
+                "swap_policy" => set(&mut config.swap_policy, SwapPolicy::from_json(val)),
+                "cleanup_policy" => set(
+                    &mut config.cleanup_policy,
+                    CgroupCleanupPolicy::from_json(val),
+                ),
+                // RLIMIT_CPU 0 would kill every process on its first tick, leave it out instead
+                "cpu_time_limit_secs" => set(
+                    &mut config.cpu_time_limit_secs,
+                    as_u32(val).filter(|&n| n > 0).map(Some),
+                ),
+                "oom_group" => set(&mut config.oom_group, val.as_bool()),
+                _ => return None,
+            })
+        });
+
+        config
+    }
+}
+
+impl LowDiskPolicy {
+    /// One of `"warn"` or `"kill"`
+    fn from_json(value: &Value) -> Option<Self> {
+        match value.as_str()? {
+            "warn" => Some(LowDiskPolicy::Warn),
+            "kill" => Some(LowDiskPolicy::Kill),
+            _ => None,
+        }
+    }
+}
+
+impl LogFormat {
+    /// One of `"text"` or `"json"`
+    fn from_json(value: &Value) -> Option<Self> {
+        match value.as_str()? {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl NetworkPolicy {
+    /// One of `"loopback_only"`, `"none"` or `"full_network"`
+    fn from_json(value: &Value) -> Option<Self> {
+        match value.as_str()? {
+            "loopback_only" => Some(NetworkPolicy::LoopbackOnly),
+            "none" => Some(NetworkPolicy::None),
+            "full_network" => Some(NetworkPolicy::FullNetwork),
+            _ => None,
+        }
+    }
+}
+
+impl CgroupCleanupPolicy {
+    /// One of `"always"`, `"on_success"`, `"on_failure"` or `"never"`
+    fn from_json(value: &Value) -> Option<Self> {
+        match value.as_str()? {
+            "always" => Some(CgroupCleanupPolicy::Always),
+            "on_success" => Some(CgroupCleanupPolicy::OnSuccess),
+            "on_failure" => Some(CgroupCleanupPolicy::OnFailure),
+            "never" => Some(CgroupCleanupPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+impl CoreDumpPolicy {
+    /// One of `"disabled"`, `"unlimited"` or `{"max_bytes": N}`
+    fn from_json(value: &Value) -> Option<Self> {
+        if let Some(name) = value.as_str() {
+            return match name {
+                "disabled" => Some(CoreDumpPolicy::Disabled),
+                "unlimited" => Some(CoreDumpPolicy::Unlimited),
+                _ => None,
+            };
+        }
+
+        match value.as_object()? {
+            [(key, n)] if key == "max_bytes" => Some(CoreDumpPolicy::MaxBytes(n.as_u64()?)),
+            _ => None,
+        }
+    }
+}
+
+impl SwapPolicy {
+    /// One of `"disabled"`, `"unlimited"`, `"same_as_memory"` or `{"max_bytes": N}`
+    fn from_json(value: &Value) -> Option<Self> {
+        if let Some(name) = value.as_str() {
+            return match name {
+                "disabled" => Some(SwapPolicy::Disabled),
+                "unlimited" => Some(SwapPolicy::Unlimited),
+                "same_as_memory" => Some(SwapPolicy::SameAsMemory),
+                _ => None,
+            };
+        }
+
+        match value.as_object()? {
+            [(key, n)] if key == "max_bytes" => Some(SwapPolicy::MaxBytes(n.as_u64()?)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_str(input: &str) -> Result<Configuration, ConfigError> {
+        Configuration::from_json(&json::parse(input).expect("valid JSON"))
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        let long = "x".repeat(1 << 20);
+        let cases = [
+            r#"[]"#.to_string(),
+            r#"{"server": 1}"#.to_string(),
+            r#"{"nonsense": {}}"#.to_string(),
+            r#"{"server": {"nonsense": 1}}"#.to_string(),
+            r#"{"server": {"max_concurrent_jobs": -1}}"#.to_string(),
+            r#"{"server": {"max_concurrent_jobs": 4294967296}}"#.to_string(),
+            r#"{"server": {"max_concurrent_jobs": 1.5}}"#.to_string(),
+            r#"{"server": {"max_concurrent_jobs": null}}"#.to_string(),
+            r#"{"server": {"max_concurrent_jobs": "4"}}"#.to_string(),
+            r#"{"sandbox": {"rlimit_nproc": -5}}"#.to_string(),
+            r#"{"server": {"tcp_listen_address": "localhost"}}"#.to_string(),
+            r#"{"server": {"tcp_listen_address": 8980}}"#.to_string(),
+            r#"{"sandbox": {"pre_spawn_hook_timeout_secs": "10"}}"#.to_string(),
+            r#"{"sandbox": {"remount_as_readonly": null}}"#.to_string(),
+            r#"{"sandbox": {"pdeathsig": "SIGNOPE"}}"#.to_string(),
+            format!(r#"{{"sandbox": {{"sandbox_hostname": "{long}"}}}}"#),
+            r#"{"cgroup": {"swap_policy": {"max_bytes": -1}}}"#.to_string(),
+            r#"{"cgroup": {"psi_threshold": null}}"#.to_string(),
+        ];
+        for case in &cases {
+            match from_str(case) {
+                Err(ConfigError::ValidationFailed(errors)) => assert!(!errors.is_empty()),
+                other => panic!("{:.80}: expected a validation error, got {other:?}", case),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_every_bad_key() {
+        let Err(ConfigError::ValidationFailed(errors)) =
+            from_str(r#"{"server": {"nonsense": 1, "cpu_id_offset": -1}, "other": null}"#)
+        else {
+            panic!("expected a validation error");
+        };
+        assert_eq!(errors.len(), 3, "{errors:?}");
+    }
+
+    #[test]
+    fn cpu_time_limit_must_be_positive() {
+        let Err(ConfigError::ValidationFailed(errors)) =
+            from_str(r#"{"cgroup": {"cpu_time_limit_secs": 0}}"#)
+        else {
+            panic!("a zero CPU time limit was accepted");
+        };
+        assert_eq!(errors, ["cgroup.cpu_time_limit_secs: invalid value 0"]);
+
+        let config = from_str(r#"{"cgroup": {"cpu_time_limit_secs": 600}}"#).unwrap();
+        assert_eq!(config.cgroup.cpu_time_limit_secs, Some(600));
+    }
+
+    #[test]
+    fn empty_document_is_the_default() {
+        let config = from_str("{}").unwrap();
+        assert_eq!(config.describe(), Configuration::default().describe());
+    }
+
+    fn write_files(contents: &[&str]) -> (tempfile::TempDir, Vec<PathBuf>) {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                let path = dir.path().join(format!("{i}.json"));
+                std::fs::write(&path, content).unwrap();
+                path
+            })
+            .collect();
+        (dir, paths)
+    }
+
+    fn load(paths: &[PathBuf]) -> Result<Configuration, ConfigError> {
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        Configuration::new(&paths)
+    }
+
+    #[test]
+    fn later_files_are_merged_over_earlier_ones() {
+        let (_dir, paths) = write_files(&[
+            r#"{"server": {"max_concurrent_jobs": 4, "cpu_id_offset": 2},
+                "sandbox": {"sysctl_overrides": {"net/core/somaxconn": "1024"}}}"#,
+            r#"{"server": {"max_concurrent_jobs": 8},
+                "sandbox": {"sysctl_overrides": {"kernel/shmmax": "4096"}},
+                "cgroup": {"oom_group": false}}"#,
+        ]);
+        let config = load(&paths).unwrap();
+
+        assert_eq!(config.server.max_concurrent_jobs, Some(8));
+        assert_eq!(config.server.cpu_id_offset, 2);
+        assert_eq!(
+            config.sandbox.sysctl_overrides,
+            HashMap::from([
+                ("net/core/somaxconn".to_string(), "1024".to_string()),
+                ("kernel/shmmax".to_string(), "4096".to_string()),
+            ])
+        );
+        assert!(!config.cgroup.oom_group);
+    }
+
+    #[test]
+    fn unknown_key_in_a_later_file_is_an_error() {
+        let (_dir, paths) = write_files(&[
+            r#"{"server": {"max_concurrent_jobs": 4}}"#,
+            r#"{"server": {"max_concurent_jobs": 8}}"#,
+        ]);
+        let Err(ConfigError::ValidationFailed(errors)) = load(&paths) else {
+            panic!("expected a validation error");
+        };
+        assert_eq!(errors, ["server.max_concurent_jobs: unknown key"]);
+    }
+
+    #[test]
+    fn missing_file_is_read_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.json");
+        match load(std::slice::from_ref(&path)) {
+            Err(ConfigError::ReadFailed { path: p, source }) => {
+                assert_eq!(p, path);
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("expected ReadFailed, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn invalid_json_is_parse_failed() {
+        let (_dir, paths) = write_files(&[r#"{"server": {"max_concurrent_jobs": 4"#]);
+        match load(&paths) {
+            Err(ConfigError::JsonParseFailed { path, source }) => {
+                assert_eq!(path, paths[0]);
+                assert_eq!(source.msg, "expected ',' or '}'");
+            }
+            other => panic!("expected JsonParseFailed, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn bad_value_is_validation_failed() {
+        let (_dir, paths) = write_files(&[r#"{"server": {"max_concurrent_jobs": 0}}"#]);
+        let Err(ConfigError::ValidationFailed(errors)) = load(&paths) else {
+            panic!("expected a validation error");
+        };
+        assert_eq!(errors, ["server.max_concurrent_jobs: invalid value 0"]);
+    }
+
+    #[test]
+    fn every_broken_file_is_reported() {
+        let (dir, mut paths) = write_files(&["{", "{}"]);
+        paths.push(dir.path().join("missing.json"));
+        match load(&paths) {
+            Err(ConfigError::Multiple(errors)) => {
+                assert!(matches!(
+                    errors[..],
+                    [
+                        ConfigError::JsonParseFailed { .. },
+                        ConfigError::ReadFailed { .. }
+                    ]
+                ));
+            }
+            other => panic!("expected Multiple, got {other:?}"),
+        }
+    }
+}
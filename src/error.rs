@@ -0,0 +1,248 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use prost::Message;
+use prost_types::Any as PbAny;
+use tonic::{Code, Status};
+
+use nix::errno::Errno;
+
+use crate::child::{
+    child_error_description, CHILD_EXEC_FAILED, CHILD_PROC_MOUNT_FAILED, CHILD_REMOUNT_FAILED,
+};
+use crate::proto::google::rpc::{ErrorInfo, RetryInfo, Status as RpcStatus};
+
+/// `ErrorInfo.domain` of every error returned by the runner
+const ERROR_DOMAIN: &str = "buildbarn.runner";
+
+pub(crate) type RunnerResult<T> = std::result::Result<T, RunnerError>;
+
+/// Failures setting up or running a job
+#[derive(Debug)]
+pub(crate) enum RunnerError {
+    /// Cloning the child into its namespaces, or finishing their setup, failed
+    Namespace(io::Error),
+    /// A cgroup control file could not be accessed
+    Cgroup { path: PathBuf, source: io::Error },
+    /// A file in the build directory could not be created
+    BuildDirectory { path: PathBuf, source: io::Error },
+    /// pid1 failed to set up the sandbox, `code` is one of `CHILD_ERROR_CODES`
+    ChildSetup { code: isize, message: String },
+    /// A system call made through nix failed, `op` says which and on what
+    Nix {
+        op: &'static str,
+        source: nix::Error,
+    },
+}
+
+/// Names the operation a nix call was for, so its error says more than the errno
+pub(crate) trait NixResultExt<T> {
+    fn ctx(self, op: &'static str) -> RunnerResult<T>;
+}
+
+impl<T> NixResultExt<T> for nix::Result<T> {
+    fn ctx(self, op: &'static str) -> RunnerResult<T> {
+        self.map_err(|source| RunnerError::Nix { op, source })
+    }
+}
+
+/// Prefer [`NixResultExt::ctx`], this only knows the errno
+impl From<nix::Error> for RunnerError {
+    fn from(source: nix::Error) -> Self {
+        RunnerError::Nix {
+            op: "system call",
+            source,
+        }
+    }
+}
+
+impl RunnerError {
+    /// What an operator can do about the error, where the errno or stage points at a likely cause
+    pub fn remediation(&self) -> Option<&'static str> {
+        let errno = |e: &io::Error| e.raw_os_error().map(Errno::from_raw);
+        match self {
+            RunnerError::Namespace(e) => match errno(e)? {
+                Errno::EPERM | Errno::EUSERS | Errno::ENOSPC => Some(
+                    "Check that /proc/sys/user/max_user_namespaces is non-zero, and that \
+                     kernel.unprivileged_userns_clone=1 where the kernel has it, or give the \
+                     runner CAP_SYS_ADMIN",
+                ),
+                Errno::EINVAL => Some(
+                    "The kernel lacks one of the namespaces, check the namespace support \
+                     logged at startup",
+                ),
+                _ => None,
+            },
+            RunnerError::Cgroup { source, .. } => match errno(source)? {
+                Errno::EACCES | Errno::EPERM | Errno::EROFS => Some(
+                    "The runner needs a writable, delegated cgroup, e.g. run it in a systemd \
+                     unit with Delegate=yes",
+                ),
+                Errno::ENOENT | Errno::EOPNOTSUPP => Some(
+                    "Check that cgroup2 is mounted at /sys/fs/cgroup and the controllers are \
+                     listed in the parent's cgroup.subtree_control",
+                ),
+                _ => None,
+            },
+            RunnerError::BuildDirectory { source, .. } => match errno(source)? {
+                Errno::EROFS => Some(
+                    "The build directory's filesystem is read-only, check the kernel log for \
+                     disk errors and remount it read-write",
+                ),
+                Errno::ENOSPC | Errno::EDQUOT => Some("Free up space in the build directory"),
+                Errno::EACCES | Errno::EPERM => {
+                    Some("The build directory must be writable by the user the runner runs as")
+                }
+                Errno::ENOENT => Some(
+                    "The parent directory is missing, check that bb_worker and the runner \
+                     share the build directory",
+                ),
+                _ => None,
+            },
+            RunnerError::Nix { .. } => None,
+            RunnerError::ChildSetup { code, .. } => match *code {
+                CHILD_PROC_MOUNT_FAILED => Some(
+                    "Mounting /proc needs an unobstructed /proc on the host, in a container \
+                     one without masked paths, e.g. --security-opt systempaths=unconfined",
+                ),
+                CHILD_REMOUNT_FAILED => Some(
+                    "Check that every host_path of sandbox.additional_bind_mounts exists and \
+                     its sandbox_path exists in the sandbox",
+                ),
+                CHILD_EXEC_FAILED => Some(
+                    "Check that the command exists in the input root, is executable and its \
+                     interpreter or dynamic loader is present",
+                ),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// What failed and why, followed by the [`remediation`](RunnerError::remediation) if there is one
+impl fmt::Display for RunnerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunnerError::Namespace(e) => write!(f, "namespace setup failed: {e}"),
+            RunnerError::Cgroup { path, source } => {
+                write!(f, "cgroup setup failed at {}: {source}", path.display())
+            }
+            RunnerError::BuildDirectory { path, source } => {
+                write!(
+                    f,
+                    "build directory unusable at {}: {source}",
+                    path.display()
+                )
+            }
+            RunnerError::ChildSetup { code, message } => {
+                write!(f, "{}: {message}", child_error_description(*code))
+            }
+            RunnerError::Nix { op, source } => write!(f, "{op} failed: {source}"),
+        }?;
+        if let Some(hint) = self.remediation() {
+            write!(f, ". {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RunnerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RunnerError::Namespace(e) => Some(e),
+            RunnerError::Cgroup { source, .. } => Some(source),
+            RunnerError::BuildDirectory { source, .. } => Some(source),
+            RunnerError::ChildSetup { .. } => None,
+            RunnerError::Nix { source, .. } => Some(source),
+        }
+    }
+}
+
+impl From<RunnerError> for Status {
+    fn from(err: RunnerError) -> Self {
+        let error = err.to_string();
+        match err {
+            RunnerError::Namespace(_) => status_with_error_info(
+                Code::Internal,
+                "Failed to spawn child",
+                "NAMESPACE_UNAVAILABLE",
+                &[("error", &error)],
+            ),
+            RunnerError::Cgroup { ref path, .. } => status_with_error_info(
+                Code::Internal,
+                "Failed to configure cgroup",
+                "CGROUP_WRITE_FAILED",
+                &[("path", &path.to_string_lossy()), ("error", &error)],
+            ),
+            RunnerError::BuildDirectory { ref path, .. } => status_with_error_info(
+                Code::Internal,
+                "Failed to create output file",
+                "BUILD_DIRECTORY_UNAVAILABLE",
+                &[("path", &path.to_string_lossy()), ("error", &error)],
+            ),
+            RunnerError::ChildSetup { code, .. } => status_with_error_info(
+                Code::Internal,
+                "Failed to set up the sandbox",
+                "CHILD_SETUP_FAILED",
+                &[
+                    ("stage", child_error_description(code)),
+                    ("code", &code.to_string()),
+                    ("error", &error),
+                ],
+            ),
+            RunnerError::Nix { op, .. } => status_with_error_info(
+                Code::Internal,
+                "System call failed",
+                "SYSCALL_FAILED",
+                &[("operation", op), ("error", &error)],
+            ),
+        }
+    }
+}
+
+/// Build a Status carrying a `google.rpc.ErrorInfo` in its details, so clients can tell errors
+/// apart by `reason` instead of parsing the message.
+pub(crate) fn status_with_error_info(
+    code: Code,
+    message: &str,
+    reason: &str,
+    metadata: &[(&str, &str)],
+) -> Status {
+    status_with_details(code, message, reason, metadata, Vec::new())
+}
+
+/// [`status_with_error_info`] with more details after the ErrorInfo
+pub(crate) fn status_with_details(
+    code: Code,
+    message: &str,
+    reason: &str,
+    metadata: &[(&str, &str)],
+    extra: Vec<PbAny>,
+) -> Status {
+    let info = ErrorInfo {
+        reason: reason.to_string(),
+        domain: ERROR_DOMAIN.to_string(),
+        metadata: metadata
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+    };
+
+    let details = RpcStatus {
+        code: code as i32,
+        message: message.to_string(),
+        details: PbAny::from_msg(&info).into_iter().chain(extra).collect(),
+    };
+
+    Status::with_details(code, message, details.encode_to_vec().into())
+}
+
+/// A `google.rpc.RetryInfo` detail, for [`status_with_details`]
+pub(crate) fn retry_info(delay: Duration) -> Option<PbAny> {
+    let info = RetryInfo {
+        retry_delay: prost_types::Duration::try_from(delay).ok(),
+    };
+    PbAny::from_msg(&info).ok()
+}
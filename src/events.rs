@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::resource::{ExitReason, ExitResources};
+
+/// Identifies one Run request for the lifetime of the runner process
+pub(crate) type JobId = u64;
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn next_job_id() -> JobId {
+    NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum JobEventKind {
+    /// Waiting for a job slot
+    Queued,
+    /// Got a job slot
+    Dequeued,
+    Started {
+        pid: u32,
+        processor: u32,
+    },
+    Finished {
+        exit_reason: ExitReason,
+        resources: ExitResources,
+    },
+    /// The job's pid1 was killed by `signal`, sent after `Finished`
+    Killed {
+        signal: i32,
+    },
+    /// Memory used by the running job's cgroup, every `memory_sample_interval_secs`
+    MemorySampled {
+        processor: u32,
+        bytes: u64,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct JobEvent {
+    pub job_id: JobId,
+    pub kind: JobEventKind,
+    pub timestamp: Instant,
+}
+
+impl JobEvent {
+    pub fn new(job_id: JobId, kind: JobEventKind) -> Self {
+        Self {
+            job_id,
+            kind,
+            timestamp: Instant::now(),
+        }
+    }
+}
+
+pub(crate) type EventSender = mpsc::UnboundedSender<JobEvent>;
+
+/// Sends to the sink, if there is one. A subscriber that went away is not an error for the job.
+pub(crate) fn emit(tx: Option<&EventSender>, job_id: JobId, kind: JobEventKind) {
+    if let Some(tx) = tx {
+        let _ = tx.send(JobEvent::new(job_id, kind));
+    }
+}
+
+/// Subscriber that just traces every event
+pub(crate) async fn log_events(mut rx: mpsc::UnboundedReceiver<JobEvent>) {
+    let start = Instant::now();
+    while let Some(event) = rx.recv().await {
+        let at = event.timestamp.duration_since(start);
+        match event.kind {
+            JobEventKind::Queued => debug!(job = event.job_id, ?at, "job queued"),
+            JobEventKind::Dequeued => debug!(job = event.job_id, ?at, "job dequeued"),
+            JobEventKind::Started { pid, processor } => {
+                debug!(job = event.job_id, ?at, pid, processor, "job started")
+            }
+            JobEventKind::Finished {
+                exit_reason,
+                resources,
+            } => debug!(
+                job = event.job_id,
+                ?at,
+                ?exit_reason,
+                exit = ?resources.status,
+                "job finished"
+            ),
+            JobEventKind::Killed { signal } => {
+                debug!(job = event.job_id, ?at, signal, "job killed")
+            }
+            JobEventKind::MemorySampled { processor, bytes } => {
+                debug!(job = event.job_id, ?at, processor, bytes, "job memory")
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use nix::libc;
+use nix::mount::{self, MsFlags};
+use nix::sched::CloneFlags;
+use nix::unistd;
+use tracing::debug;
+
+use crate::cgroup::{detect_cgroup_version, CgroupVersion};
+use crate::child::probe_clone;
+
+static FEATURES: OnceLock<FeatureProbe> = OnceLock::new();
+
+/// Optional kernel features, detected at runtime so the scheduler can route actions that need
+/// them to runners that have them.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FeatureProbe {
+    pub overlayfs: bool,
+    pub seccomp: bool,
+    pub cgroup_v2: bool,
+    pub user_namespaces: bool,
+    pub pidfd: bool,
+}
+
+impl FeatureProbe {
+    /// Probes the features on the first call, later calls return the cached result
+    pub fn get() -> &'static FeatureProbe {
+        FEATURES.get_or_init(Self::detect)
+    }
+
+    fn detect() -> Self {
+        Self {
+            overlayfs: probe_overlayfs(),
+            seccomp: probe_seccomp(),
+            cgroup_v2: detect_cgroup_version() == CgroupVersion::V2,
+            user_namespaces: probe_clone(CloneFlags::CLONE_NEWUSER, || 0).unwrap_or(false),
+            pidfd: probe_pidfd(),
+        }
+    }
+
+    /// Names of the supported features
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            ("overlayfs", self.overlayfs),
+            ("seccomp", self.seccomp),
+            ("cgroup_v2", self.cgroup_v2),
+            ("user_namespaces", self.user_namespaces),
+            ("pidfd", self.pidfd),
+        ]
+        .into_iter()
+        .filter_map(|(name, ok)| ok.then_some(name))
+        .collect()
+    }
+}
+
+/// Mounts an overlayfs on a scratch tmpfs, in a child with its own user and mount namespace so
+/// nothing is left behind on the host.
+fn probe_overlayfs() -> bool {
+    let flags = CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS;
+    let res = probe_clone(flags, || match try_mount_overlayfs() {
+        Ok(()) => 0,
+        Err(_) => 1,
+    });
+
+    debug!("overlayfs probe = {:?}", res);
+    res.unwrap_or(false)
+}
+
+fn try_mount_overlayfs() -> nix::Result<()> {
+    let scratch = Path::new("/tmp");
+    mount::mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )?;
+    mount::mount(
+        Some("tmpfs"),
+        scratch,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        None::<&str>,
+    )?;
+
+    for dir in ["lower", "upper", "work", "merged"] {
+        unistd::mkdir(&scratch.join(dir), nix::sys::stat::Mode::S_IRWXU)?;
+    }
+    mount::mount(
+        Some("overlay"),
+        &scratch.join("merged"),
+        Some("overlay"),
+        MsFlags::empty(),
+        Some("lowerdir=/tmp/lower,upperdir=/tmp/upper,workdir=/tmp/work"),
+    )
+}
+
+/// The `Seccomp` line only exists in `/proc/self/status` on kernels built with seccomp
+fn probe_seccomp() -> bool {
+    fs::read_to_string("/proc/self/status")
+        .map(|status| status.lines().any(|l| l.starts_with("Seccomp:")))
+        .unwrap_or(false)
+}
+
+fn probe_pidfd() -> bool {
+    let pid = unistd::getpid().as_raw();
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return false;
+    }
+
+    unsafe { libc::close(fd as libc::c_int) };
+    true
+}
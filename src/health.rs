@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Shared readiness of the runner, reported through CheckReadiness
+///
+/// Several independent checks can mark the runner as not ready, each under its own name, so one
+/// check recovering does not hide a failure reported by another.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Health(Arc<Mutex<BTreeMap<&'static str, String>>>);
+
+impl Health {
+    /// Returns true if the check was passing until now, periodic checks only log that flip
+    pub fn set_unhealthy(&self, check: &'static str, reason: String) -> bool {
+        let mut failing = self.0.lock().unwrap();
+        failing.insert(check, reason).is_none()
+    }
+
+    /// Returns true if the check was failing until now
+    pub fn set_healthy(&self, check: &'static str) -> bool {
+        let mut failing = self.0.lock().unwrap();
+        failing.remove(check).is_some()
+    }
+
+    /// Ok if no check is failing, otherwise all failures joined together
+    pub fn status(&self) -> Result<(), String> {
+        let failing = self.0.lock().unwrap();
+        if failing.is_empty() {
+            return Ok(());
+        }
+
+        let reasons: Vec<String> = failing.iter().map(|(k, v)| format!("{k}: {v}")).collect();
+        Err(reasons.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_transitions_are_reported() {
+        let health = Health::default();
+        assert!(!health.set_healthy("psi"));
+        assert!(health.set_unhealthy("psi", "cpu avg10=90.00".to_string()));
+        assert!(!health.set_unhealthy("psi", "cpu avg10=95.00".to_string()));
+        assert_eq!(health.status(), Err("psi: cpu avg10=95.00".to_string()));
+        assert!(health.set_healthy("psi"));
+        assert!(!health.set_healthy("psi"));
+        assert_eq!(health.status(), Ok(()));
+    }
+
+    #[test]
+    fn checks_fail_independently() {
+        let health = Health::default();
+        assert!(health.set_unhealthy("psi", "high".to_string()));
+        assert!(health.set_unhealthy("cgroup", "gone".to_string()));
+        assert_eq!(health.status(), Err("cgroup: gone; psi: high".to_string()));
+        assert!(health.set_healthy("cgroup"));
+        assert_eq!(health.status(), Err("psi: high".to_string()));
+    }
+}
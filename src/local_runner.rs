@@ -1,30 +1,64 @@
+use nix::libc::pid_t;
+use nix::sys::statvfs::statvfs;
 use std::convert::AsRef;
 use std::fs::File;
-use std::path::Path;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Component, Path, PathBuf};
 use std::process::Stdio;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 use tonic::Result as TonicResult;
 use tonic::Status;
 use tracing::{self, debug, error, info, warn};
 
-use crate::child::{Child, Command, Wait4};
+use crate::capabilities;
+use crate::cgroup::{
+    cleanup_job_cgroup, cpuacct_dir_v1, detect_cgroup_version, read_cpu_stat, read_cpuacct_usage,
+    read_memory_io_stat, read_oom_kills, CgroupVersion, MemoryEventsWatch, RealCgroupWriter,
+};
+use crate::child::{wait4_blocking, Child, Command, PidFd, SpawnResult, Wait4};
+use crate::config::{Configuration, LowDiskPolicy, SandboxConfig, ServerConfig};
+use crate::error::{RunnerError, RunnerResult};
+use crate::features::FeatureProbe;
 use crate::proto::runner::RunRequest;
 use crate::resource::ExitResources;
+use crate::seccomp::{self, SeccompProfile};
 
-const WAIT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+const WAIT_INTERVAL: Duration = Duration::from_secs(5);
 
-fn builddir_file<P: AsRef<Path>>(builddir: P, fname: &String) -> TonicResult<File> {
+/// `path` from the request must stay within the directory it is joined to, `join` would happily
+/// replace the base with an absolute path and `..` could walk out of it
+fn validate_within(field: &str, path: &str) -> TonicResult<()> {
+    let escapes = Path::new(path)
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        return Err(Status::invalid_argument(format!(
+            "{field} must be a relative path without '..', got {path:?}"
+        )));
+    }
+    Ok(())
+}
+
+fn builddir_file<P: AsRef<Path>>(builddir: P, fname: &String) -> RunnerResult<File> {
     let wdpath = builddir.as_ref().join(fname);
 
-    File::create(wdpath).or(Err(Status::internal("Failed to create stdout")))
+    File::create(&wdpath).map_err(|source| RunnerError::BuildDirectory {
+        path: wdpath,
+        source,
+    })
 }
 
 /// SIGCHILD signal handlers are global for the whole process, you can't register a handler
 /// specifically for one child only.
 /// Additionally, the kernel can coalese signals. If two children exit, the kernel is allowed to
 /// send only one single SIGCHILD.
-/// Epoll on a PidFd would probably be more reliable, try that later.
+/// So where the kernel has pidfds, its pidfd is watched too and the child is reaped as soon as it
+/// becomes readable, SIGCHLD and the interval are then just a fallback.
 ///
 /// buildbarn runner is just responsible for spawning children, It does not _do_ anything that
 /// interesting, the children do all the intensive work, so a few extra syscalls every few
@@ -32,43 +66,150 @@ fn builddir_file<P: AsRef<Path>>(builddir: P, fname: &String) -> TonicResult<Fil
 ///
 /// TL;DR: Wait for SIGCHILD, and also just timeout and test once in a while anyway, will
 /// eventually reap the child.
-#[tracing::instrument(ret, fields(child = %child.id()))]
+///
+/// A job times out after `timeout` from its request or `job_timeout_secs`, whichever is shorter,
+/// its resources are then returned with `timed_out` set.
+///
+/// A cancelled or timed out job gets SIGTERM, then SIGKILL after the grace period. If it still
+/// hasn't been reaped `cleanup_timeout_secs` after that, it is given up on. So does a job with
+/// an OOM kill in its cgroup, which is noticed as soon as `memory.events` changes on cgroup v2,
+/// unless `cgroup.oom_group` is off.
+#[tracing::instrument(ret, skip(config), fields(child = %child.id()))]
 pub(crate) async fn wait_child(
     child: &mut Child,
+    cgroup_dir: Option<&Path>,
+    builddir: &Path,
     token: CancellationToken,
+    timeout: Option<Duration>,
+    config: &Configuration,
 ) -> TonicResult<ExitResources> {
     let mut sig = signal(SignalKind::child())?;
     let mut interval = tokio::time::interval(WAIT_INTERVAL);
-    let mut kill_sent: bool = false;
+    let mut phase = StopPhase::Running;
+    let timeout = match (
+        timeout,
+        config.server.job_timeout_secs.map(Duration::from_secs),
+    ) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    let mut escalate_at = timeout.map(|t| Instant::now() + t);
+    let mut timed_out = false;
+    // Without memory.oom.group an OOM kill is one process, the job carries on if it can
+    let oom_watch = match (cgroup_dir, detect_cgroup_version()) {
+        (Some(cgroup_dir), CgroupVersion::V2) if config.cgroup.oom_group => {
+            MemoryEventsWatch::new(cgroup_dir)
+                .map_err(|e| warn!("Failed to watch memory.events: {}", e))
+                .ok()
+        }
+        _ => None,
+    };
+    let pidfd = if FeatureProbe::get().pidfd {
+        child
+            .pidfd()
+            .and_then(AsyncFd::new)
+            .map_err(|e| warn!("Failed to watch pidfd: {}", e))
+            .ok()
+    } else {
+        None
+    };
 
     loop {
+        let mut exited = false;
         // The first tick() always finishes immediately, so we can try the child right away in case
         // it has already finished.
+        // A disabled branch still evaluates its future, hence the placeholder deadline.
+        let escalate = tokio::time::sleep_until(escalate_at.unwrap_or_else(Instant::now));
         tokio::select! {
-            _ = sig.recv() => {
+            // Every child of the runner raises SIGCHLD, the pidfd only wakes us for this one
+            _ = sig.recv(), if pidfd.is_none() => {
                 debug!("Received SIGCHILD");
             }
-            _ = interval.tick() => {}
-            _ = token.cancelled(), if !kill_sent => {
-                // The token was cancelled, send SIGKILL to start cleanup
-                // Only need to kill the direct child, it is pid1 in the PID namespace which forces
-                // cleanup of all processes in the namespace.
-                match child.kill() {
-                    Ok(_) => kill_sent = true,
-                    _ => {},
+            _ = pidfd_readable(pidfd.as_ref()) => {
+                exited = true;
+            }
+            _ = interval.tick() => {
+                if low_on_disk(builddir, &config.sandbox)
+                    && config.sandbox.free_disk_policy == LowDiskPolicy::Kill
+                    && phase == StopPhase::Running
+                {
+                    warn!(pid = child.id(), "Stopping job, build directory is low on space");
+                    escalate_at = Some(stop_child(child, &mut phase, &config.server));
+                }
+            }
+            _ = memory_events_changed(oom_watch.as_ref()), if phase == StopPhase::Running => {
+                // One OOM kill is usually fatal for the build anyway, don't let it limp along
+                let oom_kills = cgroup_dir.and_then(read_oom_kills).unwrap_or(0);
+                if oom_kills > 0 {
+                    warn!(pid = child.id(), oom_kills, "Stopping job, it was OOM killed");
+                    escalate_at = Some(stop_child(child, &mut phase, &config.server));
                 }
             }
+            _ = token.cancelled(), if phase == StopPhase::Running => {
+                escalate_at = Some(stop_child(child, &mut phase, &config.server));
+            }
+            _ = escalate, if escalate_at.is_some() => {
+                if phase == StopPhase::Running {
+                    warn!(pid = child.id(), "Job timed out");
+                    timed_out = true;
+                }
+                if phase == StopPhase::Killed {
+                    error!(pid = child.id(), "Child still not reaped after SIGKILL, giving up");
+                    return Err(Status::internal("Child unresponsive"));
+                }
+                escalate_at = Some(stop_child(child, &mut phase, &config.server));
+            }
         };
 
         info!(
-            pid = child.id(),
+            ?child,
             cancelled = token.is_cancelled(),
-            kill_sent = kill_sent,
+            phase = ?phase,
             "waiting"
         );
-        match child.try_wait4() {
+        let waited = if exited {
+            let pid = child.id() as pid_t;
+            tokio::task::spawn_blocking(move || wait4_blocking(pid).map(Some))
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+        } else {
+            child.try_wait4()
+        };
+        match waited {
             Ok(None) => {}
-            Ok(Some(e)) => return Ok(e),
+            Ok(Some(mut e)) => {
+                // The cgroup outlives the processes in it, so the totals are still there
+                match (cgroup_dir, detect_cgroup_version()) {
+                    (Some(cgroup_dir), CgroupVersion::V2) => {
+                        e.cpu_stat = read_cpu_stat(cgroup_dir)
+                            .map_err(|err| warn!("Failed to read cpu.stat: {}", err))
+                            .ok();
+                        e.memory_io_stat = read_memory_io_stat(cgroup_dir)
+                            .map_err(|err| warn!("Failed to read memory and io stats: {}", err))
+                            .ok();
+                    }
+                    (Some(cgroup_dir), CgroupVersion::V1) => {
+                        let usage = cpuacct_dir_v1(cgroup_dir).and_then(|d| read_cpuacct_usage(&d));
+                        match usage {
+                            Some(total) => e.rusage.raise_cpu_total(total),
+                            None => warn!("Failed to read cpuacct.usage"),
+                        }
+                    }
+                    (None, _) => {}
+                }
+
+                if let Some(cgroup_dir) = cgroup_dir {
+                    e.oom_kills = read_oom_kills(cgroup_dir).unwrap_or(0);
+                    cleanup_job_cgroup(
+                        &RealCgroupWriter,
+                        cgroup_dir,
+                        config.cgroup.cleanup_policy,
+                        e.reason(),
+                    );
+                }
+                e.timed_out = timed_out;
+                return Ok(e);
+            }
             Err(e) => {
                 error!(pid = child.id(), "wait error {}", e);
                 break;
@@ -80,14 +221,281 @@ pub(crate) async fn wait_child(
     Err(Status::internal("Wait failed"))
 }
 
-#[tracing::instrument(skip(builddir, run))]
-pub(crate) fn spawn_child<P: AsRef<Path>>(
+/// Waits for pid1 to exit, forever without a pidfd
+async fn pidfd_readable(pidfd: Option<&AsyncFd<PidFd>>) {
+    match pidfd {
+        Some(pidfd) => match pidfd.readable().await {
+            // Stays readable, the guard must not clear it
+            Ok(_guard) => {}
+            Err(e) => {
+                warn!("Failed to poll pidfd: {}", e);
+                std::future::pending::<()>().await;
+            }
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next change of `memory.events`, forever if it isn't watched
+async fn memory_events_changed(watch: Option<&MemoryEventsWatch>) {
+    match watch {
+        Some(watch) => {
+            if let Err(e) = watch.changed().await {
+                warn!("Failed to read memory.events notifications: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Runs the operator's hook unsandboxed, as the runner user, killing it after `timeout`
+async fn run_pre_spawn_hook(
+    hook: &[String],
+    timeout: Duration,
+    builddir: &Path,
+    ird: &Path,
+    cwd: &Path,
+) -> TonicResult<()> {
+    let mut command = tokio::process::Command::new(&hook[0]);
+    command
+        .args(&hook[1..])
+        .env("BB_BUILD_DIR", builddir)
+        .env("BB_INPUT_ROOT", ird)
+        .env("BB_WORKING_DIR", cwd)
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+
+    let output = match tokio::time::timeout(timeout, command.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            error!("Failed to run pre-spawn hook {:?}: {}", hook, e);
+            return Err(Status::internal("pre-spawn hook failed"));
+        }
+        Err(_) => {
+            error!("pre-spawn hook {:?} timed out after {:?}", hook, timeout);
+            return Err(Status::internal("pre-spawn hook timed out"));
+        }
+    };
+
+    debug!(
+        "pre-spawn hook stdout: {}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    debug!(
+        "pre-spawn hook stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    if !output.status.success() {
+        error!("pre-spawn hook {:?} exited with {}", hook, output.status);
+        return Err(Status::internal("pre-spawn hook failed"));
+    }
+    Ok(())
+}
+
+/// Runs the operator's post-exec hook unsandboxed, killing it after `timeout`
+///
+/// Failures are only logged, the job already finished and its result stands.
+pub(crate) async fn run_post_exec_hook(
+    hook: &[String],
+    timeout: Duration,
+    builddir: &Path,
+    run: &RunRequest,
+    exit: &ExitResources,
+) {
+    let mut command = tokio::process::Command::new(&hook[0]);
+    // Only one of the two is set, a command killed by a signal has no exit code
+    if let Some(code) = exit.status.code() {
+        command.env("BB_EXIT_CODE", code.to_string());
+    }
+    if let Some(signal) = exit.status.signal() {
+        command.env("BB_SIGNAL", signal.to_string());
+    }
+    command
+        .args(&hook[1..])
+        .env("BB_UTIME_US", exit.rusage.utime.as_micros().to_string())
+        .env("BB_STIME_US", exit.rusage.stime.as_micros().to_string())
+        .env("BB_MAXRSS_BYTES", exit.rusage.maxrss.to_string())
+        .env("BB_STDOUT_PATH", builddir.join(&run.stdout_path))
+        .env("BB_STDERR_PATH", builddir.join(&run.stderr_path))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+
+    match tokio::time::timeout(timeout, command.status()).await {
+        Ok(Ok(status)) if status.success() => debug!("post-exec hook finished"),
+        Ok(Ok(status)) => warn!("post-exec hook {:?} exited with {}", hook, status),
+        Ok(Err(e)) => warn!("Failed to run post-exec hook {:?}: {}", hook, e),
+        Err(_) => warn!("post-exec hook {:?} timed out after {:?}", hook, timeout),
+    }
+}
+
+/// How often the free space of the build directory is checked, by all jobs together
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The last free space check, shared by every job as they all build in the same directory
+struct DiskCheck {
+    checked_at: Option<Instant>,
+    low: bool,
+}
+
+static DISK_CHECK: Mutex<DiskCheck> = Mutex::new(DiskCheck {
+    checked_at: None,
+    low: false,
+});
+
+/// Whether the filesystem of the build directory has less than `min_free_disk_gb` available
+///
+/// Checked at most every [`DISK_CHECK_INTERVAL`], in between the last result is returned. Only
+/// crossing the threshold is logged, not every check that finds it still low.
+fn low_on_disk(builddir: &Path, config: &SandboxConfig) -> bool {
+    let Some(min_gb) = config.min_free_disk_gb else {
+        return false;
+    };
+
+    // Nothing can panic while it is locked
+    let mut check = DISK_CHECK.lock().unwrap_or_else(PoisonError::into_inner);
+    if check
+        .checked_at
+        .is_some_and(|at| at.elapsed() < DISK_CHECK_INTERVAL)
+    {
+        return check.low;
+    }
+    check.checked_at = Some(Instant::now());
+
+    let stat = match statvfs(builddir) {
+        Ok(stat) => stat,
+        Err(e) => {
+            warn!("statvfs {:?} failed: {}", builddir, e);
+            check.low = false;
+            return false;
+        }
+    };
+    let available = (stat.blocks_available() as u64).saturating_mul(stat.fragment_size() as u64);
+    let low = available < u64::from(min_gb) * 1024 * 1024 * 1024;
+
+    match (check.low, low) {
+        (false, true) => warn!(
+            "Build directory {:?} has only {} MiB free",
+            builddir,
+            available / (1024 * 1024)
+        ),
+        (true, false) => info!(
+            "Build directory {:?} has {} MiB free again",
+            builddir,
+            available / (1024 * 1024)
+        ),
+        _ => {}
+    }
+    check.low = low;
+    low
+}
+
+/// How far stopping a job has escalated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StopPhase {
+    Running,
+    Terminated,
+    Killed,
+}
+
+/// Sends the next signal to the child, returning when to escalate again
+fn stop_child(child: &mut Child, phase: &mut StopPhase, config: &ServerConfig) -> Instant {
+    if *phase == StopPhase::Running {
+        // Only the direct child is signalled, it is pid1 in the PID namespace and forces cleanup
+        // of all processes in the namespace once it exits.
+        match child.terminate() {
+            Ok(()) => {
+                *phase = StopPhase::Terminated;
+                return Instant::now() + Duration::from_secs(config.kill_grace_period_secs);
+            }
+            Err(e) => warn!(pid = child.id(), "SIGTERM failed, sending SIGKILL: {}", e),
+        }
+    }
+
+    if let Err(e) = child.kill() {
+        warn!(pid = child.id(), "SIGKILL failed: {}", e);
+    }
+    *phase = StopPhase::Killed;
+    Instant::now() + Duration::from_secs(config.cleanup_timeout_secs)
+}
+
+/// Replaces the environment of `command` with the one of the job: the request's variables, then
+/// the temporary directory and the per-job home made in it. Returns the home, with its size
+/// limit, if it should be a tmpfs.
+fn set_job_environment(
+    command: &mut std::process::Command,
+    builddir: &Path,
+    config: &Configuration,
+    run: &RunRequest,
+) -> TonicResult<Option<(PathBuf, u32)>> {
+    let mut home_tmpfs = None;
+    command.env_clear();
+    // A proto map, so a name repeated on the wire was already collapsed to its last value when
+    // decoding. The order of the variables in the command's environment is unspecified.
+    command.envs(&run.environment_variables);
+    if !run.temporary_directory.is_empty() {
+        // All three name the same scratch directory so nothing falls back to the host's /tmp:
+        // TMPDIR is what POSIX, glibc, Python and Rust check, TMP and TEMP are what many
+        // cross-platform tools look at instead, following the Windows convention.
+        let tmpdir = builddir.join(&run.temporary_directory);
+        command.env("TMPDIR", &tmpdir);
+        command.env("TMP", &tmpdir);
+        command.env("TEMP", &tmpdir);
+
+        // A per-job home, so nothing a tool caches or configures leaks into the next job
+        let homedir = tmpdir.join("home");
+        let cache_home = homedir.join(".cache");
+        let config_home = homedir.join(".config");
+        let data_home = homedir.join(".local/share");
+        for dir in [&cache_home, &config_home, &data_home] {
+            std::fs::create_dir_all(dir).map_err(|source| RunnerError::BuildDirectory {
+                path: dir.clone(),
+                source,
+            })?;
+        }
+        if let Some(size_mb) = config.sandbox.home_size_limit_mb {
+            home_tmpfs = Some((homedir.clone(), size_mb));
+        }
+        command.env("HOME", &homedir);
+        command.env("HOMEPATH", &homedir);
+        command.env("XDG_CACHE_HOME", &cache_home);
+        command.env("XDG_CONFIG_HOME", &config_home);
+        command.env("XDG_DATA_HOME", &data_home);
+    }
+    Ok(home_tmpfs)
+}
+
+#[tracing::instrument(skip(builddir, config, run))]
+pub(crate) async fn spawn_child<P: AsRef<Path>>(
     processor: u32,
+    cpu: u32,
     builddir: P,
+    config: &Configuration,
     run: &RunRequest,
-) -> TonicResult<Child> {
+) -> TonicResult<SpawnResult> {
+    validate_within("input_root_directory", &run.input_root_directory)?;
+    validate_within("working_directory", &run.working_directory)?;
+    validate_within("temporary_directory", &run.temporary_directory)?;
+    validate_within("stdout_path", &run.stdout_path)?;
+    validate_within("stderr_path", &run.stderr_path)?;
+
     let ird = builddir.as_ref().join(&run.input_root_directory);
-    let cwd = ird.join(&run.working_directory);
+    let working_directory = match run.working_directory.as_str() {
+        "" => config.sandbox.default_working_directory.as_deref(),
+        dir => Some(dir),
+    };
+    let cwd = match working_directory {
+        Some(dir) if !dir.is_empty() => ird.join(dir),
+        _ => ird.clone(),
+    };
+    if config.sandbox.working_directory_must_exist && !cwd.is_dir() {
+        return Err(Status::not_found(format!(
+            "working directory {cwd:?} does not exist"
+        )));
+    }
     let arg0 = cwd.join(&run.arguments[0]);
 
     warn!("Running cmd: {:?} {:?}", arg0, &run.arguments[1..]);
@@ -98,18 +506,282 @@ pub(crate) fn spawn_child<P: AsRef<Path>>(
     let mut command = std::process::Command::new(&arg0);
     command.args(&run.arguments[1..]);
     command.current_dir(&cwd);
-    command.env_clear();
-    command.envs(&run.environment_variables);
+    let home_tmpfs = set_job_environment(&mut command, builddir.as_ref(), config, run)?;
     command.stdin(Stdio::null());
     command.stdout(Stdio::inherit());
     command.stderr(Stdio::inherit());
 
-    let cgname = format!("{processor}");
-    Command::from(command)
+    if let Some(hook) = &config.sandbox.pre_spawn_hook {
+        let timeout = Duration::from_secs(config.sandbox.pre_spawn_hook_timeout_secs);
+        run_pre_spawn_hook(hook, timeout, builddir.as_ref(), &ird, &cwd).await?;
+    }
+
+    let mut command = Command::from(command);
+    command
         .stdout(stdout_file)
         .stderr(stderr_file)
-        .hostname("localhost")
-        .cgroup(cgname.as_str())
-        .spawn()
-        .map_err(|_| Status::internal("Failed to spawn child"))
+        .hostname(&config.sandbox.sandbox_hostname)
+        .domainname(&config.sandbox.sandbox_domainname)
+        .uts_namespace(config.sandbox.isolate_uts_namespace)
+        .network(config.sandbox.network_policy)
+        .remount_readonly(config.sandbox.remount_as_readonly)
+        .remount_sysfs(config.sandbox.remount_sysfs)
+        .time_namespace(config.sandbox.isolate_time_namespace)
+        .cgroup_namespace(config.sandbox.isolate_cgroup_namespace)
+        .bind_mounts(&config.sandbox.additional_bind_mounts)
+        .sysctls(&config.sandbox.sysctl_overrides)
+        .input_root(&ird)
+        .rlimit_nproc(config.sandbox.rlimit_nproc)
+        .rlimit_fsize_mb(config.sandbox.rlimit_fsize_mb)
+        .rlimit_core(config.sandbox.rlimit_core)
+        .no_new_privs(!config.sandbox.allow_new_privs)
+        .pdeathsig(config.sandbox.pdeathsig)
+        .cpu_time_limit(config.cgroup.cpu_time_limit_secs)
+        .cgroup(&processor.to_string(), &cpu.to_string(), &config.cgroup);
+    if let Some((homedir, size_mb)) = home_tmpfs {
+        command.home_tmpfs(&homedir, size_mb);
+    }
+    command.drop_capabilities(if config.sandbox.capability_drop_all {
+        capabilities::all_but_minimal()
+    } else {
+        // The configuration was validated, the names are known
+        config
+            .sandbox
+            .capability_drop_list
+            .iter()
+            .filter_map(|name| capabilities::capability_number(name))
+            .collect()
+    });
+    if config.sandbox.seccomp_enabled {
+        let names = match &config.sandbox.seccomp_allowlist {
+            Some(names) => names.iter().map(String::as_str).collect(),
+            None => seccomp::default_allowlist(),
+        };
+        // The configuration was validated, the names are known
+        let profile = SeccompProfile::allowlist(&names)
+            .map_err(|e| Status::internal(format!("seccomp filter: {e}")))?;
+        command.seccomp(Some(profile));
+    }
+    command.spawn().await.map_err(Status::from)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use nix::unistd::Pid;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+    /// A `sleep` in its own process group, as pid1 is, optionally ignoring SIGTERM
+    fn sleeper(ignore_term: bool) -> (std::process::Child, Child) {
+        let mut cmd = std::process::Command::new("sleep");
+        cmd.arg("30").process_group(0);
+        if ignore_term {
+            unsafe {
+                cmd.pre_exec(|| {
+                    nix::libc::signal(nix::libc::SIGTERM, nix::libc::SIG_IGN);
+                    Ok(())
+                });
+            }
+        }
+        let process = cmd.spawn().unwrap();
+        let child = Child::from_pid(Pid::from_raw(process.id() as pid_t));
+        (process, child)
+    }
+
+    fn server_config() -> ServerConfig {
+        ServerConfig {
+            kill_grace_period_secs: 7,
+            cleanup_timeout_secs: 11,
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn stop_child_terminates_first() {
+        let (mut process, mut child) = sleeper(false);
+        let mut phase = StopPhase::Running;
+
+        let start = Instant::now();
+        let escalate_at = stop_child(&mut child, &mut phase, &server_config());
+        assert_eq!(phase, StopPhase::Terminated);
+        assert!(escalate_at >= start + Duration::from_secs(7));
+        assert!(escalate_at < start + Duration::from_secs(11));
+
+        assert_eq!(process.wait().unwrap().signal(), Some(nix::libc::SIGTERM));
+    }
+
+    #[test]
+    fn stop_child_kills_after_terminate() {
+        let (mut process, mut child) = sleeper(true);
+        let mut phase = StopPhase::Running;
+        let config = server_config();
+
+        stop_child(&mut child, &mut phase, &config);
+        assert_eq!(phase, StopPhase::Terminated);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(process.try_wait().unwrap().is_none(), "SIGTERM is ignored");
+
+        let start = Instant::now();
+        let escalate_at = stop_child(&mut child, &mut phase, &config);
+        assert_eq!(phase, StopPhase::Killed);
+        assert!(escalate_at >= start + Duration::from_secs(11));
+
+        assert_eq!(process.wait().unwrap().signal(), Some(nix::libc::SIGKILL));
+    }
+
+    #[test]
+    fn stop_child_kills_when_terminate_fails() {
+        let (mut process, mut child) = sleeper(false);
+        process.kill().unwrap();
+        process.wait().unwrap();
+        let mut phase = StopPhase::Running;
+
+        // The process group is gone, so SIGTERM fails and it goes straight to SIGKILL
+        stop_child(&mut child, &mut phase, &server_config());
+        assert_eq!(phase, StopPhase::Killed);
+    }
+
+    fn hook(script: &str) -> Vec<String> {
+        ["sh", "-c", script].map(String::from).to_vec()
+    }
+
+    #[tokio::test]
+    async fn pre_spawn_hook_result() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |script: &str| {
+            let hook = hook(script);
+            let dir = dir.path().to_path_buf();
+            async move {
+                let timeout = Duration::from_secs(10);
+                run_pre_spawn_hook(&hook, timeout, &dir, &dir, &dir).await
+            }
+        };
+        run(r#"test "$BB_BUILD_DIR" = "$BB_WORKING_DIR""#)
+            .await
+            .unwrap();
+        let status = run("exit 3").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+    }
+
+    #[tokio::test]
+    async fn pre_spawn_hook_is_killed_after_the_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("pid");
+        let hook = hook(&format!("echo $$ > {}; exec sleep 30", pidfile.display()));
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(200);
+        let status = run_pre_spawn_hook(&hook, timeout, dir.path(), dir.path(), dir.path())
+            .await
+            .unwrap_err();
+        assert!(status.message().contains("timed out"), "{status:?}");
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Killed when the timed out future was dropped, tokio reaps it in the background
+        let pid: pid_t = std::fs::read_to_string(&pidfile)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let gone = async {
+            while nix::sys::signal::kill(Pid::from_raw(pid), None).is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), gone)
+            .await
+            .expect("the hook is still running");
+    }
+
+    /// `BB_EXIT_CODE` and `BB_SIGNAL` as the post-exec hook saw them
+    async fn post_exec_hook_status_env(status: std::process::ExitStatus) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("env");
+        let hook = hook(&format!(
+            r#"echo "${{BB_EXIT_CODE-unset}} ${{BB_SIGNAL-unset}}" > {}"#,
+            out.display()
+        ));
+        let exit = ExitResources {
+            status,
+            rusage: crate::resource::ResourceUsage {
+                utime: Duration::ZERO,
+                stime: Duration::ZERO,
+                maxrss: 0,
+            },
+            cpu_stat: None,
+            memory_io_stat: None,
+            oom_kills: 0,
+            timed_out: false,
+        };
+        let timeout = Duration::from_secs(10);
+        run_post_exec_hook(&hook, timeout, dir.path(), &RunRequest::default(), &exit).await;
+        std::fs::read_to_string(&out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn post_exec_hook_gets_the_exit_code_or_the_signal() {
+        let exited = std::process::ExitStatus::from_raw(3 << 8);
+        assert_eq!(post_exec_hook_status_env(exited).await, "3 unset\n");
+        let killed = std::process::ExitStatus::from_raw(nix::libc::SIGKILL);
+        assert_eq!(post_exec_hook_status_env(killed).await, "unset 9\n");
+    }
+
+    fn env_of(command: &std::process::Command) -> Vec<(String, String)> {
+        let mut envs: Vec<(String, String)> = command
+            .get_envs()
+            .map(|(k, v)| {
+                let v = v.unwrap_or_default();
+                (k.to_string_lossy().into(), v.to_string_lossy().into())
+            })
+            .collect();
+        envs.sort();
+        envs
+    }
+
+    #[test]
+    fn repeated_environment_variable_keeps_the_last_value() {
+        use prost::Message;
+        let request = |value: &str| RunRequest {
+            environment_variables: [("CC".to_string(), value.to_string())].into(),
+            ..Default::default()
+        };
+        // Every map entry is a field of its own on the wire, so a name can be sent twice
+        let mut wire = request("gcc").encode_to_vec();
+        wire.extend(request("clang").encode_to_vec());
+        let run = RunRequest::decode(wire.as_slice()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut command = std::process::Command::new("true");
+        let home =
+            set_job_environment(&mut command, dir.path(), &Configuration::default(), &run).unwrap();
+        assert_eq!(home, None);
+        assert_eq!(env_of(&command), [("CC".into(), "clang".into())]);
+    }
+
+    #[test]
+    fn temporary_directory_holds_the_job_home() {
+        let run = RunRequest {
+            temporary_directory: "tmp".to_string(),
+            environment_variables: [("HOME".to_string(), "/root".to_string())].into(),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Configuration::default();
+        config.sandbox.home_size_limit_mb = Some(64);
+        let mut command = std::process::Command::new("true");
+        let home = set_job_environment(&mut command, dir.path(), &config, &run).unwrap();
+
+        let tmp = dir.path().join("tmp");
+        let homedir = tmp.join("home");
+        assert_eq!(home, Some((homedir.clone(), 64)));
+        assert!(homedir.join(".local/share").is_dir());
+        let path = |p: &Path| p.display().to_string();
+        let envs = env_of(&command);
+        let get = |name: &str| envs.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+        // The job's variables come first, the ones of the sandbox override them
+        assert_eq!(get("HOME"), Some(path(&homedir)));
+        assert_eq!(get("TMPDIR"), Some(path(&tmp)));
+        assert_eq!(get("XDG_CACHE_HOME"), Some(path(&homedir.join(".cache"))));
+        assert_eq!(envs.len(), 8);
+    }
 }
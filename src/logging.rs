@@ -0,0 +1,105 @@
+use std::fmt;
+use std::time::SystemTime;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogFormat;
+use crate::json::Value;
+
+/// Installs the global subscriber, RUST_LOG filters it and defaults to debug
+pub(crate) fn init(format: LogFormat) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::DEBUG.into())
+        .from_env_lossy();
+    let registry = tracing_subscriber::registry().with(filter);
+
+    match format {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().event_format(JsonFormat))
+            .init(),
+    }
+}
+
+/// One JSON object per event, with the timestamp, level, target, the names of the enclosing
+/// spans and the event's fields
+///
+/// tracing-subscriber's own JSON format needs serde_json, this builds on [`Value`] instead.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let timestamp = prost_types::Timestamp::from(SystemTime::now());
+        let mut fields = vec![
+            (
+                "timestamp".to_string(),
+                Value::String(timestamp.to_string()),
+            ),
+            ("level".to_string(), Value::String(meta.level().to_string())),
+            (
+                "target".to_string(),
+                Value::String(meta.target().to_string()),
+            ),
+        ];
+
+        if let Some(scope) = ctx.event_scope() {
+            let spans = scope
+                .from_root()
+                .map(|span| Value::String(span.name().to_string()))
+                .collect();
+            fields.push(("spans".to_string(), Value::Array(spans)));
+        }
+
+        let mut visitor = JsonVisitor(fields);
+        event.record(&mut visitor);
+        writeln!(writer, "{}", Value::Object(visitor.0))
+    }
+}
+
+struct JsonVisitor(Vec<(String, Value)>);
+
+impl JsonVisitor {
+    fn push(&mut self, field: &Field, value: Value) {
+        self.0.push((field.name().to_string(), value));
+    }
+}
+
+impl Visit for JsonVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, Value::Bool(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, Value::Number(value.to_string()));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, Value::Number(value.to_string()));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, Value::String(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, Value::String(format!("{value:?}")));
+    }
+}
@@ -1,28 +1,66 @@
 #![cfg_attr(not(unix), allow(unused_imports))]
+// tonic::Status is large, but it is what every handler returns anyway
+#![allow(clippy::result_large_err)]
 
 use std::io::ErrorKind;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tonic::transport::Server;
-use tracing::{self, warn};
-use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+use tracing::{self, error, info, warn};
 
 #[cfg(unix)]
 use tokio::net::UnixListener;
 #[cfg(unix)]
 use tokio_stream::wrappers::UnixListenerStream;
 
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+
+use nix::sched::CloneFlags;
+use prost::Message;
+use prost_types::FileDescriptorSet;
+use tonic_reflection::pb::v1::server_reflection_server::SERVICE_NAME as REFLECTION_SERVICE;
+use tonic_reflection::server::v1::{ServerReflection, ServerReflectionServer};
+
+use crate::child::{
+    check_core_pattern, probe_namespace_support, probe_time_namespace, DEFAULT_NAMESPACES,
+};
+use crate::config::{Configuration, LogFormat};
+use crate::features::FeatureProbe;
+use crate::health::Health;
 use crate::proto::runner::runner_server::RunnerServer;
-use crate::service::RunnerService;
+use crate::service::{ProcessorQueue, RunnerService};
 
+mod audit;
+mod capabilities;
+mod cgroup;
 mod child;
+mod config;
+mod error;
+mod events;
+mod features;
+mod health;
+mod json;
 mod local_runner;
+mod logging;
 mod mmaps;
 mod mounts;
 mod resource;
+mod seccomp;
 mod service;
 
 pub(crate) mod proto {
+    pub(crate) mod google {
+        pub(crate) mod rpc {
+            tonic::include_proto!("google.rpc");
+        }
+    }
+    #[allow(dead_code)]
     pub(crate) mod resourceusage {
         tonic::include_proto!("buildbarn.resourceusage");
     }
@@ -33,27 +71,136 @@ pub(crate) mod proto {
         tonic::include_file_descriptor_set!("bb_descriptor");
 }
 
+/// Binds next to `path` and renames the socket into place, so a crash never leaves `path`
+/// missing, it is either the old socket or the new one
 fn bind_socket(path: &Path) -> Result<UnixListenerStream, Box<dyn std::error::Error>> {
     std::fs::create_dir_all(path.parent().unwrap())?;
-    std::fs::remove_file(path).unwrap_or_else(|error| {
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".tmp.{}", std::process::id()));
+    let tmp_path = PathBuf::from(tmp_path);
+    // Left behind by an earlier runner that crashed with the same pid
+    std::fs::remove_file(&tmp_path).unwrap_or_else(|error| {
         if error.kind() != ErrorKind::NotFound {
             panic!("Failed to remove socket: {:?}", error);
         }
     });
 
-    let socket = UnixListener::bind(path)?;
+    let socket = UnixListener::bind(&tmp_path)?;
+    std::fs::rename(&tmp_path, path)?;
     Ok(UnixListenerStream::new(socket))
 }
 
+/// Listens on `addr`, with SO_REUSEPORT if `reuse_port`, which has to be set before binding
+fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Connections of the runner that exited in TIME_WAIT don't keep the port taken
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Reflection for every service in our descriptors and the reflection service, except `deny`
+fn reflection_service(
+    deny: &[String],
+) -> Result<ServerReflectionServer<impl ServerReflection>, Box<dyn std::error::Error>> {
+    let mut builder = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET);
+    if deny.is_empty() {
+        return Ok(builder.build_v1()?);
+    }
+
+    let descriptors = FileDescriptorSet::decode(proto::FILE_DESCRIPTOR_SET)?;
+    let services = descriptors.file.iter().flat_map(|file| {
+        file.service
+            .iter()
+            .map(move |svc| format!("{}.{}", file.package(), svc.name()))
+    });
+    for service in services.chain([REFLECTION_SERVICE.to_string()]) {
+        if !deny.contains(&service) {
+            builder = builder.with_service_name(service);
+        }
+    }
+
+    Ok(builder.build_v1()?)
+}
+
+/// Every job needs these namespaces, refuse to start rather than fail every single job later
+fn check_namespace_support() {
+    let namespaces = DEFAULT_NAMESPACES | CloneFlags::CLONE_NEWUTS | CloneFlags::CLONE_NEWCGROUP;
+    let support = probe_namespace_support(namespaces);
+
+    let name = |ns: CloneFlags| ns.iter_names().next().map_or("UNKNOWN", |(n, _)| n);
+    let summary: Vec<String> = support
+        .iter()
+        .map(|&(ns, ok)| format!("{}={}", name(ns), if ok { "ok" } else { "unsupported" }))
+        .collect();
+    warn!("namespace support: {}", summary.join(", "));
+
+    let missing: Vec<CloneFlags> = support
+        .iter()
+        .filter(|&&(_, ok)| !ok)
+        .map(|&(ns, _)| ns)
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+
+    for ns in missing {
+        if ns == CloneFlags::CLONE_NEWUSER {
+            error!(
+                "User namespaces are unavailable, enable them with: \
+                 echo 65536 > /proc/sys/user/max_user_namespaces"
+            );
+        } else {
+            error!("{} is not supported by this kernel", name(ns));
+        }
+    }
+    std::process::exit(1);
+}
+
+/// Waits for SIGTERM, then stops handing out job slots and lets the running jobs finish
+async fn drain_on_sigterm(queue: ProcessorQueue, timeout: Duration) -> std::io::Result<()> {
+    signal(SignalKind::terminate())?.recv().await;
+    warn!(
+        "SIGTERM received, waiting up to {:?} for running jobs",
+        timeout
+    );
+    queue.close();
+    if tokio::time::timeout(timeout, queue.wait_for_drain())
+        .await
+        .is_err()
+    {
+        warn!("Jobs still running after {:?}, exiting anyway", timeout);
+    }
+    Ok(())
+}
+
 #[cfg(unix)]
 // CLONE_NEWUSER requires that the calling process is not threaded
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // if RUST_LOG var is not set, default to debug
-    let filter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::DEBUG.into())
-        .from_env_lossy();
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    let config_paths: Vec<&Path> = args.iter().map(Path::new).collect();
+    let config = Configuration::new(&config_paths);
+    // The log format is configured, so a broken configuration is reported as text
+    logging::init(
+        config
+            .as_ref()
+            .map_or(LogFormat::Text, |c| c.server.log_format),
+    );
+    let mut config = config.unwrap_or_else(|e| {
+        error!("Configuration error: {}", e);
+        std::process::exit(1);
+    });
+    for (key, value) in config.describe() {
+        info!(key, value, "Configuration");
+    }
 
     let base_path = std::env::current_dir()?;
     let sock_path = base_path.join("runner");
@@ -62,26 +209,97 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         bind_socket(sock_path.as_path()).unwrap_or_else(|error| {
             panic!("Failed to create socket: {:?}", error);
         });
+    let tcp_stream = match config.server.tcp_listen_address {
+        Some(addr) => {
+            let listener = bind_tcp(addr, config.server.tcp_reuse_port).unwrap_or_else(|error| {
+                panic!("Failed to listen on {}: {:?}", addr, error);
+            });
+            warn!("Also listening on {}", addr);
+            Some(TcpListenerStream::new(TcpListener::from_std(listener)?))
+        }
+        None => None,
+    };
 
     let nproc: u32 = match thread::available_parallelism() {
         Ok(p) => p.get() as u32,
         _ => 8,
     };
     warn!("Number of processors = {}", nproc);
+    info!(
+        max_concurrent_jobs = config.server.max_concurrent_jobs.unwrap_or(nproc),
+        "Effective job slots"
+    );
 
-    let bb_runner = RunnerService::new(base_path, nproc);
-    let svc = RunnerServer::new(bb_runner);
+    check_namespace_support();
+    check_core_pattern(config.sandbox.rlimit_core);
+    if config.sandbox.isolate_time_namespace && !probe_time_namespace() {
+        warn!("Time namespaces are unavailable (Linux 5.6 or later), jobs share the host's clocks");
+        config.sandbox.isolate_time_namespace = false;
+    }
+    warn!("Features: {}", FeatureProbe::get().names().join(", "));
 
-    let reflection_svc = tonic_reflection::server::Builder::configure()
-        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
-        .build_v1()?;
+    let health = Health::default();
+    tokio::spawn(cgroup::monitor_controllers(health.clone()));
+    if config.cgroup.psi_monitoring {
+        tokio::spawn(cgroup::monitor_pressure(
+            health.clone(),
+            config.cgroup.psi_threshold,
+        ));
+    }
+
+    let enable_reflection = config.server.enable_grpc_reflection;
+    let reflection_deny = config.server.grpc_reflection_deny_services.clone();
+    let decode_limit = config.server.grpc_max_decode_message_bytes;
+    let encode_limit = config.server.grpc_max_encode_message_bytes;
+    let drain_timeout = Duration::from_secs(config.server.shutdown_drain_timeout_secs);
+    let memory_sample_interval = config.server.memory_sample_interval_secs;
+    let (event_tx, event_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(events::log_events(event_rx));
+
+    let bb_runner =
+        RunnerService::new(base_path, nproc, Arc::new(config), health).with_event_sink(event_tx);
+    let processors = bb_runner.processor_queue();
+    if let Some(secs) = memory_sample_interval {
+        tokio::spawn(bb_runner.memory_sampler(Duration::from_secs(secs)));
+    }
+    let mut svc = RunnerServer::new(bb_runner);
+    if let Some(n) = decode_limit {
+        svc = svc.max_decoding_message_size(n);
+    }
+    if let Some(n) = encode_limit {
+        svc = svc.max_encoding_message_size(n);
+    }
+
+    let reflection_svc = if enable_reflection {
+        Some(reflection_service(&reflection_deny)?)
+    } else {
+        None
+    };
 
     warn!("Starting Buildbarn Runner ...");
-    Server::builder()
+    // The same services, the RPCs of both listeners share the job slots
+    let tcp_server = tcp_stream.map(|stream| {
+        Server::builder()
+            .add_service(svc.clone())
+            .add_optional_service(reflection_svc.clone())
+            .serve_with_incoming(stream)
+    });
+    let tcp_server = async {
+        match tcp_server {
+            Some(server) => server.await,
+            None => std::future::pending().await,
+        }
+    };
+    let server = Server::builder()
         .add_service(svc)
-        .add_service(reflection_svc)
-        .serve_with_incoming(socket_stream)
-        .await?;
+        .add_optional_service(reflection_svc)
+        .serve_with_incoming(socket_stream);
+    // Dropping the server closes its connections, jobs still running get their pdeathsig
+    tokio::select! {
+        res = server => res?,
+        res = tcp_server => res?,
+        res = drain_on_sigterm(processors, drain_timeout) => res?,
+    }
 
     Ok(())
 }
@@ -90,3 +308,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 fn main() {
     panic!("Only works on unix!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_port_lets_a_second_runner_bind() {
+        let first = bind_tcp("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_tcp(addr, true).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn port_is_taken_without_reuse_port() {
+        let first = bind_tcp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = first.local_addr().unwrap();
+        let err = bind_tcp(addr, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        // Both runners have to set it, the old one decides whether the port can be shared
+        let err = bind_tcp(addr, true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+    }
+}
@@ -6,7 +6,7 @@ use std::slice;
 use std::sync::Once;
 
 use nix::errno::Errno;
-use nix::sys::mman::{self, MapFlags, ProtFlags};
+use nix::sys::mman::{self, MapFlags, MmapAdvise, ProtFlags};
 use nix::unistd::{self, SysconfVar};
 
 pub fn page_size() -> usize {
@@ -24,11 +24,22 @@ pub fn page_size() -> usize {
     }
 }
 
+pub fn is_page_aligned(size: usize) -> bool {
+    size.is_multiple_of(page_size())
+}
+
+/// Rounds `size` up to whole pages, panics if that overflows
+pub fn round_up_to_page(size: usize) -> usize {
+    size.next_multiple_of(page_size())
+}
+
 #[derive(Debug)]
 pub(crate) struct StackMap {
     pub stack_size: usize,
     pub mmap_size: usize,
     pub mmap_base: NonNull<c_void>,
+    /// The stack pages are already RW, the guard page always stays PROT_NONE
+    protected: bool,
 }
 
 impl Drop for StackMap {
@@ -41,8 +52,21 @@ impl Drop for StackMap {
 }
 
 impl<'a> StackMap {
+    /// Maps a stack of `bytes` rounded up to whole pages, plus a guard page below it
+    pub fn from_size_bytes(bytes: usize) -> Result<Self, Error> {
+        if bytes == 0 || bytes > usize::MAX - page_size() {
+            return Err(Errno::EINVAL.into());
+        }
+
+        Self::new(round_up_to_page(bytes))
+    }
+
+    /// Maps a stack of exactly `stack_size` bytes plus a guard page below it
+    ///
+    /// `stack_size` must be a non-zero multiple of [`page_size()`], fails with EINVAL otherwise.
+    /// [`StackMap::from_size_bytes`] rounds up instead.
     pub fn new(stack_size: usize) -> Result<Self, Error> {
-        if stack_size % page_size() != 0 {
+        if stack_size == 0 || !is_page_aligned(stack_size) {
             return Err(Errno::EINVAL.into());
         }
 
@@ -59,19 +83,34 @@ impl<'a> StackMap {
         }?;
 
         Ok(Self {
-            stack_size: stack_size,
-            mmap_size: mmap_size,
-            mmap_base: mmap_base,
+            stack_size,
+            mmap_size,
+            mmap_base,
+            protected: false,
         })
     }
 
-    pub fn as_slice(&'a self) -> Result<&'a mut [u8], Errno> {
+    /// Drops the physical pages of the stack, the next use faults in zero filled pages
+    ///
+    /// Nothing a previous child left on the stack is visible to the next one, and it is cheaper
+    /// than a memset. The protection is unchanged.
+    pub fn clear(&self) -> Result<(), Errno> {
+        unsafe {
+            let stack_base = self.mmap_base.byte_add(page_size());
+            mman::madvise(stack_base, self.stack_size, MmapAdvise::MADV_DONTNEED)
+        }
+    }
+
+    pub fn as_slice(&'a mut self) -> Result<&'a mut [u8], Errno> {
         let rw = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
 
         unsafe {
             let stack_base = self.mmap_base.byte_add(page_size());
 
-            mman::mprotect(stack_base, self.stack_size, rw)?;
+            if !self.protected {
+                mman::mprotect(stack_base, self.stack_size, rw)?;
+                self.protected = true;
+            }
 
             Ok(slice::from_raw_parts_mut(
                 stack_base.cast().as_ptr(),
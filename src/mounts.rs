@@ -1,4 +1,5 @@
 use std::ffi::{CStr, CString};
+use std::fmt;
 use std::io::Error;
 use std::path::Path;
 use std::vec::Vec;
@@ -8,7 +9,6 @@ use nix::mount::MsFlags;
 
 pub(crate) struct MntEntOpener(*mut FILE);
 
-#[derive(Debug)]
 pub(crate) struct MntEntWrapper {
     pub mnt_fsname: String, // name of mounted filesystem
     pub mnt_dir: String,    // filesystem path prefix
@@ -19,9 +19,62 @@ pub(crate) struct MntEntWrapper {
     pub mnt_flags: MsFlags, // Mount Flags to pass to mount(2)
 }
 
+/// Mount option names of `flags`, in the style of /proc/mounts, e.g. `ro,nosuid,nodev`
+pub(crate) fn mount_option_string(flags: MsFlags) -> String {
+    const NAMES: [(MsFlags, &str); 7] = [
+        (MsFlags::MS_RDONLY, "ro"),
+        (MsFlags::MS_NOSUID, "nosuid"),
+        (MsFlags::MS_NODEV, "nodev"),
+        (MsFlags::MS_NOEXEC, "noexec"),
+        (MsFlags::MS_NOATIME, "noatime"),
+        (MsFlags::MS_NODIRATIME, "nodiratime"),
+        (MsFlags::MS_RELATIME, "relatime"),
+    ];
+
+    let names: Vec<&str> = NAMES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|&(_, name)| name)
+        .collect();
+    if names.is_empty() {
+        return "rw".to_string();
+    }
+    names.join(",")
+}
+
+impl fmt::Debug for MntEntWrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MntEntWrapper")
+            .field("mnt_fsname", &self.mnt_fsname)
+            .field("mnt_dir", &self.mnt_dir)
+            .field("mnt_type", &self.mnt_type)
+            .field("mnt_opts", &self.mnt_opts)
+            .field("mnt_freq", &self.mnt_freq)
+            .field("mnt_passno", &self.mnt_passno)
+            .field("mnt_flags", &mount_option_string(self.mnt_flags))
+            .finish()
+    }
+}
+
+impl fmt::Display for MntEntWrapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} on {} type {} ({})",
+            self.mnt_fsname,
+            self.mnt_dir,
+            self.mnt_type,
+            mount_option_string(self.mnt_flags)
+        )
+    }
+}
+
 impl std::convert::From<*mut mntent> for MntEntWrapper {
     fn from(source: *mut mntent) -> Self {
         let mut flags = MsFlags::empty();
+        if !unsafe { libc::hasmntopt(source, c"ro".as_ptr()).is_null() } {
+            flags |= MsFlags::MS_RDONLY;
+        }
         if !unsafe { libc::hasmntopt(source, c"nosuid".as_ptr()).is_null() } {
             flags |= MsFlags::MS_NOSUID;
         }
@@ -59,7 +112,7 @@ impl Drop for MntEntOpener {
     fn drop(&mut self) {
         match unsafe { libc::endmntent(self.0) } {
             1 => (),
-            ret @ _ => panic!("endmntent returned {}, expected 1", ret),
+            ret => panic!("endmntent returned {}, expected 1", ret),
         }
     }
 }
@@ -76,18 +129,75 @@ impl MntEntOpener {
         }
     }
 
-    pub fn list_all(&self) -> Result<Vec<MntEntWrapper>, Error> {
-        let mut entries = Vec::<MntEntWrapper>::new();
+    pub fn list_all(self) -> Result<Vec<MntEntWrapper>, Error> {
+        self.into_iter().collect()
+    }
+}
 
-        loop {
-            let mnt: *mut mntent = unsafe { libc::getmntent(self.0) };
-            if mnt.is_null() {
-                break;
-            }
+/// Reads the mount table one entry at a time, the table is a cursor so iterating consumes it
+impl Iterator for MntEntOpener {
+    type Item = Result<MntEntWrapper, Error>;
 
-            entries.push(MntEntWrapper::from(mnt));
+    fn next(&mut self) -> Option<Self::Item> {
+        let mnt: *mut mntent = unsafe { libc::getmntent(self.0) };
+        if mnt.is_null() {
+            return None;
         }
 
-        Ok(entries)
+        Some(Ok(MntEntWrapper::from(mnt)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_option_string_names_flags() {
+        assert_eq!(mount_option_string(MsFlags::empty()), "rw");
+        assert_eq!(mount_option_string(MsFlags::MS_RDONLY), "ro");
+        assert_eq!(
+            mount_option_string(MsFlags::MS_NOEXEC | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID),
+            "ro,nosuid,noexec"
+        );
+        // Flags without a name are left out
+        assert_eq!(mount_option_string(MsFlags::MS_BIND), "rw");
+    }
+
+    #[test]
+    fn mntent_flags_from_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let fstab = dir.path().join("fstab");
+        std::fs::write(
+            &fstab,
+            "/dev/sda1 / ext4 rw,relatime 0 1\n\
+             proc /proc proc ro,nosuid,nodev,noexec 0 0\n\
+             tmpfs /tmp tmpfs rw,noatime,nodiratime,size=10m 0 0\n",
+        )
+        .unwrap();
+
+        let mounts = MntEntOpener::new(&fstab).unwrap().list_all().unwrap();
+        assert_eq!(mounts.len(), 3);
+
+        assert_eq!(mounts[0].mnt_fsname, "/dev/sda1");
+        assert_eq!(mounts[0].mnt_dir, "/");
+        assert_eq!(mounts[0].mnt_type, "ext4");
+        assert_eq!(mounts[0].mnt_passno, 1);
+        assert_eq!(mounts[0].mnt_flags, MsFlags::MS_RELATIME);
+
+        assert_eq!(
+            mounts[1].mnt_flags,
+            MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC
+        );
+        assert_eq!(
+            mounts[1].to_string(),
+            "proc on /proc type proc (ro,nosuid,nodev,noexec)"
+        );
+
+        assert_eq!(mounts[2].mnt_opts, "rw,noatime,nodiratime,size=10m");
+        assert_eq!(
+            mounts[2].mnt_flags,
+            MsFlags::MS_NOATIME | MsFlags::MS_NODIRATIME
+        );
     }
 }
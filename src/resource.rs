@@ -1,18 +1,24 @@
 use std::process::ExitStatus;
 use std::time::Duration;
 
-use crate::proto::resourceusage::PosixResourceUsage;
+use crate::cgroup::{CpuStat, MemoryIoStat};
+use crate::proto::resourceusage::{
+    CgroupCpuResourceUsage, CgroupResourceUsage, PosixResourceUsage,
+};
 
 /// Resources used by a process
+///
+/// The CPU times come from rusage, which measures in microseconds. Raising them to the cgroup
+/// totals can leave nanoseconds behind, below what was actually measured.
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct ResourceUsage {
     /// User CPU time used
     ///
-    /// Time spent in user-mode
+    /// Time spent in user-mode, microsecond resolution
     pub utime: Duration,
     /// System CPU time used
     ///
-    /// Time spent in kernel-mode
+    /// Time spent in kernel-mode, microsecond resolution
     pub stime: Duration,
     /// Maximum resident set size, in bytes.
     ///
@@ -20,6 +26,41 @@ pub(crate) struct ResourceUsage {
     pub maxrss: u64,
 }
 
+impl ResourceUsage {
+    /// Raises the CPU time to `total` if rusage undercounted it, splitting the extra between user
+    /// and system time in the ratio rusage measured.
+    pub fn raise_cpu_total(&mut self, total: Duration) {
+        let measured = self.utime + self.stime;
+        if total <= measured {
+            return;
+        }
+
+        if measured.is_zero() {
+            self.utime = total;
+            return;
+        }
+        let user_share = self.utime.as_secs_f64() / measured.as_secs_f64();
+        self.utime = total.mul_f64(user_share);
+        self.stime = total.saturating_sub(self.utime);
+    }
+
+    /// The CPU times rounded to the nearest microsecond, so reports don't differ in digits that
+    /// were never measured
+    pub fn normalize_for_proto(&self) -> ResourceUsage {
+        let round = |d: Duration| {
+            let secs = Duration::from_secs(d.as_secs());
+            let nanos = d.subsec_nanos();
+            secs.checked_add(Duration::from_micros(((nanos + 500) / 1000).into()))
+                .unwrap_or(secs + Duration::from_micros((nanos / 1000).into()))
+        };
+        ResourceUsage {
+            utime: round(self.utime),
+            stime: round(self.stime),
+            ..*self
+        }
+    }
+}
+
 /// Resources used by a process and its exit status
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct ExitResources {
@@ -29,23 +70,272 @@ pub(crate) struct ExitResources {
     pub status: ExitStatus,
     /// Resource used by the process and all its children
     pub rusage: ResourceUsage,
+    /// CPU accounting of the job's cgroup, if it had one
+    pub cpu_stat: Option<CpuStat>,
+    /// Memory and IO accounting of the job's cgroup, only on cgroup v2
+    pub memory_io_stat: Option<MemoryIoStat>,
+    /// Processes of the job killed by the OOM killer
+    pub oom_kills: u64,
+    /// Stopped because it ran past its timeout
+    pub timed_out: bool,
 }
 
-impl Into<PosixResourceUsage> for ResourceUsage {
-    fn into(self) -> PosixResourceUsage {
+impl ExitResources {
+    pub fn reason(&self) -> ExitReason {
+        ExitReason::new(self.status, self.oom_kills)
+    }
+
+    /// Everything the job's cgroup v2 accounted for, None without one
+    pub fn cgroup_usage(&self) -> Option<CgroupResourceUsage> {
+        let cpu = self.cpu_stat?;
+        let memory_io = self.memory_io_stat?;
+        Some(CgroupResourceUsage {
+            memory_peak_bytes: memory_io.memory_peak_bytes,
+            cpu_usage_usec: cpu.usage_usec,
+            io_read_bytes: memory_io.io_read_bytes,
+            io_write_bytes: memory_io.io_write_bytes,
+            oom_kill_count: self.oom_kills,
+            nr_throttled_periods: cpu.nr_throttled,
+        })
+    }
+}
+
+/// Why a job ended, as far as deciding what to keep around for debugging
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExitReason {
+    Success,
+    /// Non-zero exit code or killed by a signal
+    Failure,
+    /// The kernel OOM killer hit something in the job's cgroup
+    OomKilled,
+}
+
+impl ExitReason {
+    pub fn new(status: ExitStatus, oom_kills: u64) -> Self {
+        if oom_kills > 0 {
+            ExitReason::OomKilled
+        } else if status.success() {
+            ExitReason::Success
+        } else {
+            ExitReason::Failure
+        }
+    }
+}
+
+impl From<ResourceUsage> for PosixResourceUsage {
+    fn from(val: ResourceUsage) -> Self {
+        let val = val.normalize_for_proto();
         let mut pbres = PosixResourceUsage::default();
-        if let Ok(n) = prost_types::Duration::try_from(self.utime) {
+        if let Ok(n) = prost_types::Duration::try_from(val.utime) {
             pbres.user_time = Some(n);
         }
 
-        if let Ok(n) = prost_types::Duration::try_from(self.stime) {
+        if let Ok(n) = prost_types::Duration::try_from(val.stime) {
             pbres.system_time = Some(n);
         }
 
-        if let Ok(n) = i64::try_from(self.maxrss) {
+        if let Ok(n) = i64::try_from(val.maxrss) {
             pbres.maximum_resident_set_size = n;
         }
 
         pbres
     }
 }
+
+impl From<CpuStat> for CgroupCpuResourceUsage {
+    fn from(val: CpuStat) -> Self {
+        let usec = |n: u64| prost_types::Duration::try_from(Duration::from_micros(n)).ok();
+
+        CgroupCpuResourceUsage {
+            usage_time: usec(val.usage_usec),
+            user_time: usec(val.user_usec),
+            system_time: usec(val.system_usec),
+            throttled_periods: val.nr_throttled,
+            throttled_time: usec(val.throttled_usec),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    fn killed(signal: i32) -> ExitStatus {
+        ExitStatus::from_raw(signal)
+    }
+
+    fn exit_resources(status: ExitStatus) -> ExitResources {
+        ExitResources {
+            status,
+            rusage: ResourceUsage {
+                utime: Duration::ZERO,
+                stime: Duration::ZERO,
+                maxrss: 0,
+            },
+            cpu_stat: None,
+            memory_io_stat: None,
+            oom_kills: 0,
+            timed_out: false,
+        }
+    }
+
+    #[test]
+    fn exit_reason() {
+        assert_eq!(ExitReason::new(exited(0), 0), ExitReason::Success);
+        assert_eq!(ExitReason::new(exited(1), 0), ExitReason::Failure);
+        assert_eq!(ExitReason::new(killed(9), 0), ExitReason::Failure);
+        // An OOM kill wins, even if the job carried on and succeeded
+        assert_eq!(ExitReason::new(killed(9), 1), ExitReason::OomKilled);
+        assert_eq!(ExitReason::new(exited(0), 2), ExitReason::OomKilled);
+    }
+
+    #[test]
+    fn cgroup_usage_needs_both_stats() {
+        let mut e = exit_resources(exited(0));
+        assert!(e.cgroup_usage().is_none());
+        e.cpu_stat = Some(CpuStat::default());
+        assert!(e.cgroup_usage().is_none());
+        e.cpu_stat = None;
+        e.memory_io_stat = Some(MemoryIoStat::default());
+        assert!(e.cgroup_usage().is_none());
+    }
+
+    #[test]
+    fn cgroup_usage() {
+        let mut e = exit_resources(killed(9));
+        e.cpu_stat = Some(CpuStat {
+            usage_usec: 1500,
+            user_usec: 1000,
+            system_usec: 500,
+            nr_throttled: 3,
+            throttled_usec: 40,
+        });
+        e.memory_io_stat = Some(MemoryIoStat {
+            memory_peak_bytes: 10 << 20,
+            io_read_bytes: 4096,
+            io_write_bytes: 8192,
+        });
+        e.oom_kills = 1;
+
+        assert_eq!(e.reason(), ExitReason::OomKilled);
+        assert_eq!(
+            e.cgroup_usage(),
+            Some(CgroupResourceUsage {
+                memory_peak_bytes: 10 << 20,
+                cpu_usage_usec: 1500,
+                io_read_bytes: 4096,
+                io_write_bytes: 8192,
+                oom_kill_count: 1,
+                nr_throttled_periods: 3,
+            })
+        );
+    }
+
+    fn usage(utime: Duration, stime: Duration) -> ResourceUsage {
+        ResourceUsage {
+            utime,
+            stime,
+            maxrss: 0,
+        }
+    }
+
+    #[test]
+    fn normalize_rounds_to_microseconds() {
+        let cases = [
+            (
+                Duration::from_nanos(1_000_000_499),
+                Duration::from_micros(1_000_000),
+            ),
+            (
+                Duration::from_nanos(1_000_000_500),
+                Duration::from_micros(1_000_001),
+            ),
+            (
+                Duration::from_micros(1_234_567),
+                Duration::from_micros(1_234_567),
+            ),
+            (Duration::from_nanos(499), Duration::ZERO),
+            (Duration::ZERO, Duration::ZERO),
+        ];
+        for (before, after) in cases {
+            let n = usage(before, before).normalize_for_proto();
+            assert_eq!((n.utime, n.stime), (after, after), "{before:?}");
+        }
+
+        // Rounding up would overflow, so the very largest value rounds down
+        let n = usage(Duration::MAX, Duration::ZERO).normalize_for_proto();
+        assert_eq!(n.utime, Duration::new(u64::MAX, 999_999_000));
+    }
+
+    #[test]
+    fn posix_resource_usage_round_trips() {
+        let durations = [
+            Duration::ZERO,
+            Duration::from_micros(1),
+            Duration::from_micros(999_999),
+            Duration::from_secs(1),
+            Duration::from_micros(86_400_123_456),
+            // The largest time a proto Duration holds
+            Duration::new(315_576_000_000, 999_999_000),
+        ];
+        for &utime in &durations {
+            for &stime in &durations {
+                let usage = ResourceUsage {
+                    utime,
+                    stime,
+                    maxrss: 10 << 20,
+                };
+                let pb = PosixResourceUsage::from(usage);
+                let back =
+                    |d: Option<prost_types::Duration>| Duration::try_from(d.unwrap()).unwrap();
+                assert_eq!(back(pb.user_time), utime);
+                assert_eq!(back(pb.system_time), stime);
+                assert_eq!(pb.maximum_resident_set_size, 10 << 20);
+            }
+        }
+    }
+
+    #[test]
+    fn posix_resource_usage_out_of_range() {
+        let pb = PosixResourceUsage::from(ResourceUsage {
+            utime: Duration::MAX,
+            stime: Duration::from_secs(1),
+            maxrss: u64::MAX,
+        });
+        assert!(pb.user_time.is_none());
+        assert!(pb.system_time.is_some());
+        assert_eq!(pb.maximum_resident_set_size, 0);
+    }
+
+    #[test]
+    fn raise_cpu_total() {
+        // Never lowered
+        let mut u = usage(Duration::from_secs(3), Duration::from_secs(1));
+        u.raise_cpu_total(Duration::from_secs(2));
+        assert_eq!(
+            (u.utime, u.stime),
+            (Duration::from_secs(3), Duration::from_secs(1))
+        );
+
+        // Split in the measured ratio, adding up to the total
+        u.raise_cpu_total(Duration::from_secs(8));
+        assert_eq!(
+            (u.utime, u.stime),
+            (Duration::from_secs(6), Duration::from_secs(2))
+        );
+
+        let mut u = usage(Duration::from_micros(333), Duration::from_micros(667));
+        u.raise_cpu_total(Duration::from_nanos(1_000_001));
+        assert_eq!(u.utime + u.stime, Duration::from_nanos(1_000_001));
+
+        // Nothing measured, all of it is user time
+        let mut u = usage(Duration::ZERO, Duration::ZERO);
+        u.raise_cpu_total(Duration::from_secs(5));
+        assert_eq!((u.utime, u.stime), (Duration::from_secs(5), Duration::ZERO));
+    }
+}
@@ -0,0 +1,476 @@
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+
+use nix::libc::{self, c_long, c_uint, sock_filter};
+
+/// `AUDIT_ARCH_*` of the runner's own architecture, other ABIs number their syscalls differently
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// x32 syscalls share x86_64's audit arch and are told apart by this bit in the number
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Every syscall an allowlist can name, and whether the default allowlist has it. The default is
+/// what typical build tools need: file and directory operations, processes, memory mappings,
+/// signals, time, and sockets for the tools that talk to a local server.
+const SYSCALLS: &[(&str, c_long, bool)] = &[
+    // Files and directories
+    ("read", libc::SYS_read, true),
+    ("write", libc::SYS_write, true),
+    ("readv", libc::SYS_readv, true),
+    ("writev", libc::SYS_writev, true),
+    ("pread64", libc::SYS_pread64, true),
+    ("pwrite64", libc::SYS_pwrite64, true),
+    ("preadv", libc::SYS_preadv, true),
+    ("pwritev", libc::SYS_pwritev, true),
+    ("openat", libc::SYS_openat, true),
+    ("close", libc::SYS_close, true),
+    ("close_range", libc::SYS_close_range, true),
+    ("lseek", libc::SYS_lseek, true),
+    ("fstat", libc::SYS_fstat, true),
+    ("newfstatat", libc::SYS_newfstatat, true),
+    ("statx", libc::SYS_statx, true),
+    ("statfs", libc::SYS_statfs, true),
+    ("fstatfs", libc::SYS_fstatfs, true),
+    ("faccessat", libc::SYS_faccessat, true),
+    ("faccessat2", libc::SYS_faccessat2, true),
+    ("getdents64", libc::SYS_getdents64, true),
+    ("getcwd", libc::SYS_getcwd, true),
+    ("chdir", libc::SYS_chdir, true),
+    ("fchdir", libc::SYS_fchdir, true),
+    ("mkdirat", libc::SYS_mkdirat, true),
+    ("unlinkat", libc::SYS_unlinkat, true),
+    ("renameat2", libc::SYS_renameat2, true),
+    ("linkat", libc::SYS_linkat, true),
+    ("symlinkat", libc::SYS_symlinkat, true),
+    ("readlinkat", libc::SYS_readlinkat, true),
+    ("fchmod", libc::SYS_fchmod, true),
+    ("fchmodat", libc::SYS_fchmodat, true),
+    ("fchown", libc::SYS_fchown, true),
+    ("fchownat", libc::SYS_fchownat, true),
+    ("umask", libc::SYS_umask, true),
+    ("utimensat", libc::SYS_utimensat, true),
+    ("truncate", libc::SYS_truncate, true),
+    ("ftruncate", libc::SYS_ftruncate, true),
+    ("fallocate", libc::SYS_fallocate, true),
+    ("fadvise64", libc::SYS_fadvise64, true),
+    ("fsync", libc::SYS_fsync, true),
+    ("fdatasync", libc::SYS_fdatasync, true),
+    ("flock", libc::SYS_flock, true),
+    ("fcntl", libc::SYS_fcntl, true),
+    ("ioctl", libc::SYS_ioctl, true),
+    ("dup", libc::SYS_dup, true),
+    ("dup3", libc::SYS_dup3, true),
+    ("pipe2", libc::SYS_pipe2, true),
+    ("sendfile", libc::SYS_sendfile, true),
+    ("copy_file_range", libc::SYS_copy_file_range, true),
+    ("splice", libc::SYS_splice, true),
+    ("tee", libc::SYS_tee, true),
+    ("memfd_create", libc::SYS_memfd_create, true),
+    ("getxattr", libc::SYS_getxattr, true),
+    ("lgetxattr", libc::SYS_lgetxattr, true),
+    ("fgetxattr", libc::SYS_fgetxattr, true),
+    ("listxattr", libc::SYS_listxattr, true),
+    ("llistxattr", libc::SYS_llistxattr, true),
+    ("flistxattr", libc::SYS_flistxattr, true),
+    ("setxattr", libc::SYS_setxattr, true),
+    ("lsetxattr", libc::SYS_lsetxattr, true),
+    ("fsetxattr", libc::SYS_fsetxattr, true),
+    ("inotify_init1", libc::SYS_inotify_init1, true),
+    ("inotify_add_watch", libc::SYS_inotify_add_watch, true),
+    ("inotify_rm_watch", libc::SYS_inotify_rm_watch, true),
+    // Processes
+    ("clone", libc::SYS_clone, true),
+    ("clone3", libc::SYS_clone3, true),
+    ("execve", libc::SYS_execve, true),
+    ("execveat", libc::SYS_execveat, true),
+    ("exit", libc::SYS_exit, true),
+    ("exit_group", libc::SYS_exit_group, true),
+    ("wait4", libc::SYS_wait4, true),
+    ("waitid", libc::SYS_waitid, true),
+    ("kill", libc::SYS_kill, true),
+    ("tkill", libc::SYS_tkill, true),
+    ("tgkill", libc::SYS_tgkill, true),
+    ("pidfd_open", libc::SYS_pidfd_open, true),
+    ("pidfd_send_signal", libc::SYS_pidfd_send_signal, true),
+    ("getpid", libc::SYS_getpid, true),
+    ("getppid", libc::SYS_getppid, true),
+    ("gettid", libc::SYS_gettid, true),
+    ("setpgid", libc::SYS_setpgid, true),
+    ("getpgid", libc::SYS_getpgid, true),
+    ("setsid", libc::SYS_setsid, true),
+    ("getuid", libc::SYS_getuid, true),
+    ("getgid", libc::SYS_getgid, true),
+    ("geteuid", libc::SYS_geteuid, true),
+    ("getegid", libc::SYS_getegid, true),
+    ("getgroups", libc::SYS_getgroups, true),
+    ("getresuid", libc::SYS_getresuid, true),
+    ("getresgid", libc::SYS_getresgid, true),
+    ("capget", libc::SYS_capget, true),
+    ("prctl", libc::SYS_prctl, true),
+    ("prlimit64", libc::SYS_prlimit64, true),
+    ("getrusage", libc::SYS_getrusage, true),
+    ("times", libc::SYS_times, true),
+    ("uname", libc::SYS_uname, true),
+    ("sysinfo", libc::SYS_sysinfo, true),
+    ("getpriority", libc::SYS_getpriority, true),
+    ("setpriority", libc::SYS_setpriority, true),
+    ("sched_yield", libc::SYS_sched_yield, true),
+    ("sched_getaffinity", libc::SYS_sched_getaffinity, true),
+    ("sched_setaffinity", libc::SYS_sched_setaffinity, true),
+    ("sched_getparam", libc::SYS_sched_getparam, true),
+    ("sched_getscheduler", libc::SYS_sched_getscheduler, true),
+    (
+        "sched_get_priority_max",
+        libc::SYS_sched_get_priority_max,
+        true,
+    ),
+    (
+        "sched_get_priority_min",
+        libc::SYS_sched_get_priority_min,
+        true,
+    ),
+    ("set_tid_address", libc::SYS_set_tid_address, true),
+    ("set_robust_list", libc::SYS_set_robust_list, true),
+    ("get_robust_list", libc::SYS_get_robust_list, true),
+    ("futex", libc::SYS_futex, true),
+    ("rseq", libc::SYS_rseq, true),
+    ("membarrier", libc::SYS_membarrier, true),
+    ("getrandom", libc::SYS_getrandom, true),
+    // Memory
+    ("brk", libc::SYS_brk, true),
+    ("mmap", libc::SYS_mmap, true),
+    ("munmap", libc::SYS_munmap, true),
+    ("mprotect", libc::SYS_mprotect, true),
+    ("mremap", libc::SYS_mremap, true),
+    ("msync", libc::SYS_msync, true),
+    ("mincore", libc::SYS_mincore, true),
+    ("madvise", libc::SYS_madvise, true),
+    ("mlock", libc::SYS_mlock, true),
+    ("munlock", libc::SYS_munlock, true),
+    // Signals
+    ("rt_sigaction", libc::SYS_rt_sigaction, true),
+    ("rt_sigprocmask", libc::SYS_rt_sigprocmask, true),
+    ("rt_sigreturn", libc::SYS_rt_sigreturn, true),
+    ("rt_sigsuspend", libc::SYS_rt_sigsuspend, true),
+    ("rt_sigtimedwait", libc::SYS_rt_sigtimedwait, true),
+    ("sigaltstack", libc::SYS_sigaltstack, true),
+    ("signalfd4", libc::SYS_signalfd4, true),
+    ("restart_syscall", libc::SYS_restart_syscall, true),
+    // Time and waiting
+    ("nanosleep", libc::SYS_nanosleep, true),
+    ("clock_nanosleep", libc::SYS_clock_nanosleep, true),
+    ("clock_gettime", libc::SYS_clock_gettime, true),
+    ("clock_getres", libc::SYS_clock_getres, true),
+    ("gettimeofday", libc::SYS_gettimeofday, true),
+    ("getitimer", libc::SYS_getitimer, true),
+    ("setitimer", libc::SYS_setitimer, true),
+    ("timerfd_create", libc::SYS_timerfd_create, true),
+    ("timerfd_settime", libc::SYS_timerfd_settime, true),
+    ("timerfd_gettime", libc::SYS_timerfd_gettime, true),
+    ("epoll_create1", libc::SYS_epoll_create1, true),
+    ("epoll_ctl", libc::SYS_epoll_ctl, true),
+    ("epoll_pwait", libc::SYS_epoll_pwait, true),
+    ("ppoll", libc::SYS_ppoll, true),
+    ("pselect6", libc::SYS_pselect6, true),
+    ("eventfd2", libc::SYS_eventfd2, true),
+    // Sockets
+    ("socket", libc::SYS_socket, true),
+    ("socketpair", libc::SYS_socketpair, true),
+    ("connect", libc::SYS_connect, true),
+    ("bind", libc::SYS_bind, true),
+    ("listen", libc::SYS_listen, true),
+    ("accept", libc::SYS_accept, true),
+    ("accept4", libc::SYS_accept4, true),
+    ("shutdown", libc::SYS_shutdown, true),
+    ("getsockname", libc::SYS_getsockname, true),
+    ("getpeername", libc::SYS_getpeername, true),
+    ("setsockopt", libc::SYS_setsockopt, true),
+    ("getsockopt", libc::SYS_getsockopt, true),
+    ("sendto", libc::SYS_sendto, true),
+    ("recvfrom", libc::SYS_recvfrom, true),
+    ("sendmsg", libc::SYS_sendmsg, true),
+    ("recvmsg", libc::SYS_recvmsg, true),
+    // Rarely needed, but harmless
+    ("openat2", libc::SYS_openat2, true),
+    ("sync", libc::SYS_sync, true),
+    ("syncfs", libc::SYS_syncfs, true),
+    ("sync_file_range", libc::SYS_sync_file_range, true),
+    ("removexattr", libc::SYS_removexattr, true),
+    ("lremovexattr", libc::SYS_lremovexattr, true),
+    ("fremovexattr", libc::SYS_fremovexattr, true),
+    ("getsid", libc::SYS_getsid, true),
+    ("rt_sigpending", libc::SYS_rt_sigpending, true),
+    // The older calls that x86_64 still has and its binaries still use
+    #[cfg(target_arch = "x86_64")]
+    ("open", libc::SYS_open, true),
+    #[cfg(target_arch = "x86_64")]
+    ("stat", libc::SYS_stat, true),
+    #[cfg(target_arch = "x86_64")]
+    ("lstat", libc::SYS_lstat, true),
+    #[cfg(target_arch = "x86_64")]
+    ("access", libc::SYS_access, true),
+    #[cfg(target_arch = "x86_64")]
+    ("getdents", libc::SYS_getdents, true),
+    #[cfg(target_arch = "x86_64")]
+    ("mkdir", libc::SYS_mkdir, true),
+    #[cfg(target_arch = "x86_64")]
+    ("rmdir", libc::SYS_rmdir, true),
+    #[cfg(target_arch = "x86_64")]
+    ("unlink", libc::SYS_unlink, true),
+    #[cfg(target_arch = "x86_64")]
+    ("rename", libc::SYS_rename, true),
+    #[cfg(target_arch = "x86_64")]
+    ("renameat", libc::SYS_renameat, true),
+    #[cfg(target_arch = "x86_64")]
+    ("link", libc::SYS_link, true),
+    #[cfg(target_arch = "x86_64")]
+    ("symlink", libc::SYS_symlink, true),
+    #[cfg(target_arch = "x86_64")]
+    ("readlink", libc::SYS_readlink, true),
+    #[cfg(target_arch = "x86_64")]
+    ("chmod", libc::SYS_chmod, true),
+    #[cfg(target_arch = "x86_64")]
+    ("chown", libc::SYS_chown, true),
+    #[cfg(target_arch = "x86_64")]
+    ("lchown", libc::SYS_lchown, true),
+    #[cfg(target_arch = "x86_64")]
+    ("creat", libc::SYS_creat, true),
+    #[cfg(target_arch = "x86_64")]
+    ("pipe", libc::SYS_pipe, true),
+    #[cfg(target_arch = "x86_64")]
+    ("dup2", libc::SYS_dup2, true),
+    #[cfg(target_arch = "x86_64")]
+    ("fork", libc::SYS_fork, true),
+    #[cfg(target_arch = "x86_64")]
+    ("vfork", libc::SYS_vfork, true),
+    #[cfg(target_arch = "x86_64")]
+    ("poll", libc::SYS_poll, true),
+    #[cfg(target_arch = "x86_64")]
+    ("select", libc::SYS_select, true),
+    #[cfg(target_arch = "x86_64")]
+    ("epoll_create", libc::SYS_epoll_create, true),
+    #[cfg(target_arch = "x86_64")]
+    ("epoll_wait", libc::SYS_epoll_wait, true),
+    #[cfg(target_arch = "x86_64")]
+    ("eventfd", libc::SYS_eventfd, true),
+    #[cfg(target_arch = "x86_64")]
+    ("inotify_init", libc::SYS_inotify_init, true),
+    #[cfg(target_arch = "x86_64")]
+    ("getrlimit", libc::SYS_getrlimit, true),
+    #[cfg(target_arch = "x86_64")]
+    ("setrlimit", libc::SYS_setrlimit, true),
+    #[cfg(target_arch = "x86_64")]
+    ("getpgrp", libc::SYS_getpgrp, true),
+    #[cfg(target_arch = "x86_64")]
+    ("arch_prctl", libc::SYS_arch_prctl, true),
+    #[cfg(target_arch = "x86_64")]
+    ("alarm", libc::SYS_alarm, true),
+    #[cfg(target_arch = "x86_64")]
+    ("pause", libc::SYS_pause, true),
+    #[cfg(target_arch = "x86_64")]
+    ("time", libc::SYS_time, true),
+    // Not allowed by default, but likely to be added by an operator
+    ("mknodat", libc::SYS_mknodat, false),
+    ("rt_sigqueueinfo", libc::SYS_rt_sigqueueinfo, false),
+    ("ptrace", libc::SYS_ptrace, false),
+    ("personality", libc::SYS_personality, false),
+];
+
+/// Allowlist of jobs whose configuration doesn't list their syscalls
+pub(crate) fn default_allowlist() -> Vec<&'static str> {
+    SYSCALLS
+        .iter()
+        .filter(|&&(_, _, default)| default)
+        .map(|&(name, _, _)| name)
+        .collect()
+}
+
+fn syscall_number(name: &str) -> Option<c_long> {
+    SYSCALLS
+        .iter()
+        .find(|&&(n, _, _)| n == name)
+        .map(|&(_, nr, _)| nr)
+}
+
+/// A seccomp BPF program, applied with [`SeccompProfile::apply`]
+#[derive(Clone, Debug)]
+pub(crate) struct SeccompProfile {
+    filter: Vec<sock_filter>,
+}
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+impl SeccompProfile {
+    /// Allows exactly `syscalls`, every other one fails with EPERM. A syscall of another ABI
+    /// kills the process, its number would mean something else.
+    ///
+    /// Fails for a name this architecture has no syscall for.
+    pub fn allowlist(syscalls: &[&str]) -> Result<Self> {
+        let nrs = syscalls
+            .iter()
+            .map(|&name| {
+                syscall_number(name).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, format!("unknown syscall {name:?}"))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let ret_errno = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+        let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+        let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+        let ret = libc::BPF_RET | libc::BPF_K;
+
+        let mut filter = vec![
+            stmt(load, mem::offset_of!(libc::seccomp_data, arch) as u32),
+            jump(jeq, AUDIT_ARCH, 1, 0),
+            stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(load, mem::offset_of!(libc::seccomp_data, nr) as u32),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ),
+            stmt(ret, ret_errno),
+        ]);
+        // One comparison per syscall, each one jumps to its own return so no offset gets large
+        for nr in nrs {
+            filter.push(jump(jeq, nr as u32, 0, 1));
+            filter.push(stmt(ret, libc::SECCOMP_RET_ALLOW));
+        }
+        filter.push(stmt(ret, ret_errno));
+
+        Ok(Self { filter })
+    }
+
+    /// Installs the filter on the calling thread, which keeps it across execve along with its
+    /// children. Sets no_new_privs first, the filter can't be installed without it unprivileged,
+    /// and pid1 may not have set it with `allow_new_privs`. A filter synced to every thread with
+    /// SECCOMP_FILTER_FLAG_TSYNC needs it set before just the same.
+    ///
+    /// Only makes raw syscalls, so it is safe between fork and exec.
+    pub fn apply(&self) -> Result<()> {
+        let prog = libc::sock_fprog {
+            len: self.filter.len() as u16,
+            filter: self.filter.as_ptr() as *mut sock_filter,
+        };
+
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        let r = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER as c_uint,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if r < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_syscall_is_rejected() {
+        let err = SeccompProfile::allowlist(&["read", "no_such_syscall"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("no_such_syscall"), "{err}");
+    }
+
+    #[test]
+    fn default_allowlist_compiles() {
+        let allowlist = default_allowlist();
+        assert!(SeccompProfile::allowlist(&allowlist).is_ok());
+    }
+
+    #[test]
+    fn filter_layout() {
+        let profile = SeccompProfile::allowlist(&["read", "write", "exit_group"]).unwrap();
+        let filter = &profile.filter;
+
+        // Arch check, x32 rejection on x86_64, a comparison and return per syscall, the default
+        let x32 = if cfg!(target_arch = "x86_64") { 2 } else { 0 };
+        assert_eq!(filter.len(), 4 + x32 + 2 * 3 + 1);
+
+        assert_eq!(filter[1].k, AUDIT_ARCH);
+        assert_eq!(filter[2].k, libc::SECCOMP_RET_KILL_PROCESS);
+        let last = filter.last().unwrap();
+        assert_eq!(last.code as u32, libc::BPF_RET | libc::BPF_K);
+        assert_eq!(last.k, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+
+        // Each comparison falls through to its ALLOW, or skips it to the next comparison
+        let first = 4 + x32;
+        assert_eq!(filter[first].k, libc::SYS_read as u32);
+        assert_eq!((filter[first].jt, filter[first].jf), (0, 1));
+        assert_eq!(filter[first + 1].k, libc::SECCOMP_RET_ALLOW);
+    }
+
+    /// Runs `f` in a forked child under `profile`, returning whether it succeeded. Between fork
+    /// and exit the child only makes raw syscalls, the test harness is multi-threaded.
+    fn run_filtered(profile: &SeccompProfile, f: fn() -> bool) -> bool {
+        match unsafe { libc::fork() } {
+            0 => {
+                let ok = profile.apply().is_ok() && f();
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            pid => {
+                assert!(pid > 0, "fork failed: {}", Error::last_os_error());
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            }
+        }
+    }
+
+    fn open_fails_with_eperm() -> bool {
+        let fd = unsafe { libc::open(c"/proc/self/stat".as_ptr(), libc::O_RDONLY) };
+        fd < 0 && Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    fn open_succeeds() -> bool {
+        let fd = unsafe { libc::open(c"/proc/self/stat".as_ptr(), libc::O_RDONLY) };
+        fd >= 0
+    }
+
+    #[test]
+    fn filtered_child_cannot_open_files() {
+        let without_open: Vec<&str> = default_allowlist()
+            .into_iter()
+            .filter(|name| !matches!(*name, "open" | "openat" | "openat2" | "creat"))
+            .collect();
+        let profile = SeccompProfile::allowlist(&without_open).unwrap();
+        assert!(run_filtered(&profile, open_fails_with_eperm));
+
+        let profile = SeccompProfile::allowlist(&default_allowlist()).unwrap();
+        assert!(run_filtered(&profile, open_succeeds));
+    }
+}
@@ -1,63 +1,388 @@
 use prost_types::Any as PbAny;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::convert::AsRef;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use tonic::Result as TonicResult;
-use tonic::Status;
-use tracing::{self, debug, info};
+use tonic::{Code, Status};
+use tracing::{self, debug, error, info};
 
 #[cfg(unix)]
 use tonic::transport::server::UdsConnectInfo;
 
-use crate::proto::resourceusage::PosixResourceUsage;
+use crate::proto::resourceusage::{CgroupCpuResourceUsage, PosixResourceUsage};
 use crate::proto::runner::runner_server::Runner;
-use crate::proto::runner::{CheckReadinessRequest, RunRequest, RunResponse};
+use crate::proto::runner::{
+    CheckReadinessRequest, FreezeJobRequest, RunRequest, RunResponse, UnfreezeJobRequest,
+};
 
-use crate::local_runner::{spawn_child, wait_child};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::cgroup::{
+    detect_cgroup_version, read_memory_current, set_frozen, CgroupVersion, RealCgroupWriter,
+};
+use crate::child::SpawnResult;
+use crate::config::Configuration;
+use crate::error::{retry_info, status_with_details};
+use crate::events::{emit, next_job_id, EventSender, JobEventKind, JobId};
+use crate::health::Health;
+use crate::local_runner::{run_post_exec_hook, spawn_child, wait_child};
 use crate::resource::ExitResources;
 
+/// Job slots, a semaphore with one permit per slot limits how many are taken and the free slot
+/// numbers are handed out along with the permits.
+///
+/// A permit is only released after its slot number is back, so holding one means a number is
+/// free. The std mutex is only held to push or pop a number, never across an await, so
+/// [`ProcessorGuard`] can give the slot back from `drop`.
 #[derive(Clone, Debug)]
-struct ProcessorQueue(Arc<Mutex<VecDeque<u32>>>);
+pub(crate) struct ProcessorQueue(Arc<QueueState>);
+
+#[derive(Debug)]
+struct QueueState {
+    /// Closed once the runner is shutting down, no more slots are handed out
+    permits: Arc<Semaphore>,
+    total: usize,
+    slots: std::sync::Mutex<Slots>,
+    /// Woken whenever the last taken slot comes back
+    drained: Notify,
+}
+
+#[derive(Debug)]
+struct Slots {
+    free: VecDeque<u32>,
+    /// Moving average of how long slots are held, None until the first one is given back
+    mean_lease: Option<Duration>,
+}
+
+/// Jobs between two write probes of the build directory, while it is writable
+const WRITE_PROBE_INTERVAL_JOBS: u64 = 32;
+
+/// Weight of the latest lease in [`Slots::mean_lease`]
+const LEASE_EWMA_WEIGHT: f64 = 0.2;
+
+/// A taken job slot, given back when dropped, even if the job's task panics
+#[derive(Debug)]
+pub(crate) struct ProcessorGuard {
+    queue: ProcessorQueue,
+    processor: u32,
+    taken: Instant,
+    /// Only None while being dropped
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+/// A running job that has a cgroup
+#[derive(Clone, Debug)]
+struct ActiveJob {
+    job_id: JobId,
+    cgroup_dir: PathBuf,
+}
+
+/// The running jobs with a cgroup, by processor
+#[derive(Clone, Debug, Default)]
+struct ActiveJobs(Arc<Mutex<HashMap<u32, ActiveJob>>>);
 
 #[derive(Debug)]
 pub(crate) struct RunnerService {
     builddir: PathBuf,
+    /// Result of the last write probe, a read-only build directory is probed again on every
+    /// request until it recovers
+    builddir_writable: AtomicBool,
+    jobs_since_probe: AtomicU64,
     processors: ProcessorQueue,
+    /// Job slots beyond the CPU count share CPUs round-robin
+    nproc: u32,
+    jobs: ActiveJobs,
+    config: Arc<Configuration>,
+    health: Health,
+    audit: Option<Arc<AuditLog>>,
+    event_tx: Option<EventSender>,
 }
 
 impl ProcessorQueue {
     pub fn new(deque: VecDeque<u32>) -> Self {
-        Self(Arc::new(Mutex::new(deque)))
+        Self(Arc::new(QueueState {
+            permits: Arc::new(Semaphore::new(deque.len())),
+            total: deque.len(),
+            slots: std::sync::Mutex::new(Slots {
+                free: deque,
+                mean_lease: None,
+            }),
+            drained: Notify::new(),
+        }))
     }
 
-    pub async fn take_cpu(&self) -> TonicResult<u32> {
-        let m = self.0.clone();
-        let mut q = m.lock().await;
-        q.pop_front()
-            .ok_or(Status::resource_exhausted("No available concurrency slots"))
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slots> {
+        // Nothing can panic while the queue is locked, it is never left half updated
+        self.0.slots.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
-    pub async fn give_cpu(&self, cpu: u32) {
-        let m = self.0.clone();
-        let mut q = m.lock().await;
-        q.push_back(cpu)
+    fn taken(&self) -> usize {
+        self.0.total - self.0.permits.available_permits()
+    }
+
+    /// Never waits for a slot, the caller is told when to retry instead
+    pub fn take_cpu(&self) -> TonicResult<ProcessorGuard> {
+        let permit = match self.0.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::Closed) => return Err(Status::unavailable("shutting down")),
+            Err(TryAcquireError::NoPermits) => {
+                // Jobs finish at about `mean_lease / taken` intervals when they are staggered
+                let taken = self.taken().max(1) as u32;
+                let retry = self
+                    .lock()
+                    .mean_lease
+                    .and_then(|mean| retry_info(mean / taken));
+                return Err(status_with_details(
+                    Code::ResourceExhausted,
+                    "No available concurrency slots",
+                    "NO_FREE_SLOTS",
+                    &[],
+                    retry.into_iter().collect(),
+                ));
+            }
+        };
+        let processor = self
+            .lock()
+            .free
+            .pop_front()
+            .expect("a free slot number for every permit");
+        Ok(ProcessorGuard {
+            queue: self.clone(),
+            processor,
+            taken: Instant::now(),
+            permit: Some(permit),
+        })
+    }
+
+    fn give_cpu(&self, cpu: u32, held: Duration, permit: OwnedSemaphorePermit) {
+        {
+            let mut slots = self.lock();
+            slots.free.push_back(cpu);
+            slots.mean_lease = Some(match slots.mean_lease {
+                Some(mean) => {
+                    mean.mul_f64(1.0 - LEASE_EWMA_WEIGHT) + held.mul_f64(LEASE_EWMA_WEIGHT)
+                }
+                None => held,
+            });
+        }
+        drop(permit);
+        if self.taken() == 0 {
+            self.0.drained.notify_waiters();
+        }
+    }
+
+    /// Refuse every later [`take_cpu`](Self::take_cpu), running jobs keep their slots
+    pub fn close(&self) {
+        self.0.permits.close();
+    }
+
+    /// Resolves once every taken slot has been given back
+    pub async fn wait_for_drain(&self) {
+        loop {
+            let drained = self.0.drained.notified();
+            tokio::pin!(drained);
+            // Registered before checking, so a slot given back in between still wakes us
+            drained.as_mut().enable();
+            if self.taken() == 0 {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+impl ProcessorGuard {
+    pub fn processor(&self) -> u32 {
+        self.processor
+    }
+}
+
+impl Drop for ProcessorGuard {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.queue
+                .give_cpu(self.processor, self.taken.elapsed(), permit);
+        }
+    }
+}
+
+impl ActiveJobs {
+    pub async fn insert(&self, processor: u32, job_id: JobId, cgroup_dir: PathBuf) {
+        let job = ActiveJob { job_id, cgroup_dir };
+        self.0.lock().await.insert(processor, job);
+    }
+
+    pub async fn remove(&self, processor: u32) {
+        self.0.lock().await.remove(&processor);
+    }
+
+    pub async fn get(&self, processor: u32) -> Option<PathBuf> {
+        let jobs = self.0.lock().await;
+        jobs.get(&processor).map(|job| job.cgroup_dir.clone())
+    }
+
+    /// The jobs running now, so their cgroups can be read without holding the lock
+    async fn snapshot(&self) -> Vec<(u32, ActiveJob)> {
+        let jobs = self.0.lock().await;
+        jobs.iter().map(|(p, job)| (*p, job.clone())).collect()
+    }
+}
+
+/// Emits the memory use of every running job once. A job finishing meanwhile takes its cgroup
+/// with it, that is not worth more than a debug line.
+async fn sample_job_memory(jobs: &ActiveJobs, version: CgroupVersion, event_tx: &EventSender) {
+    for (processor, job) in jobs.snapshot().await {
+        match read_memory_current(&RealCgroupWriter, version, &job.cgroup_dir) {
+            Ok(bytes) => emit(
+                Some(event_tx),
+                job.job_id,
+                JobEventKind::MemorySampled { processor, bytes },
+            ),
+            Err(e) => debug!(processor, "Failed to sample the job's memory: {}", e),
+        }
     }
 }
 
 impl RunnerService {
-    pub fn new<P: AsRef<Path>>(builddir: P, nproc: u32) -> RunnerService {
-        let p: Vec<u32> = (0..nproc).collect();
+    pub fn new<P: AsRef<Path>>(
+        builddir: P,
+        nproc: u32,
+        config: Arc<Configuration>,
+        health: Health,
+    ) -> RunnerService {
+        let slots = config.server.max_concurrent_jobs.unwrap_or(nproc);
+        let offset = config.server.cpu_id_offset;
+        let p: Vec<u32> = (offset..offset.saturating_add(slots)).collect();
+        let audit = config
+            .server
+            .audit_log_path
+            .as_ref()
+            .map(|path| Arc::new(AuditLog::new(path, config.server.audit_log_max_size_bytes)));
+        let builddir = PathBuf::from(builddir.as_ref()).join("build");
+        let writable = probe_writable(&builddir, &health);
         Self {
-            builddir: PathBuf::from(builddir.as_ref()).join("build"),
+            builddir,
+            builddir_writable: AtomicBool::new(writable),
+            jobs_since_probe: AtomicU64::new(0),
             processors: ProcessorQueue::new(p.into()),
+            nproc,
+            jobs: ActiveJobs::default(),
+            config,
+            health,
+            audit,
+            event_tx: None,
+        }
+    }
+
+    /// Handle on the job slots, for draining them on shutdown
+    pub fn processor_queue(&self) -> ProcessorQueue {
+        self.processors.clone()
+    }
+
+    /// Publish the lifecycle of every job to `tx`
+    pub fn with_event_sink(mut self, tx: EventSender) -> Self {
+        self.event_tx = Some(tx);
+        self
+    }
+
+    /// Samples the memory use of the running jobs every `interval` into the event sink, for
+    /// watching long jobs before they finish. Runs until dropped, does nothing without a sink.
+    pub fn memory_sampler(&self, interval: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let jobs = self.jobs.clone();
+        let event_tx = self.event_tx.clone();
+        async move {
+            let Some(event_tx) = event_tx else {
+                return;
+            };
+            let version = detect_cgroup_version();
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                sample_job_memory(&jobs, version, &event_tx).await;
+            }
+        }
+    }
+
+    /// Whether the build directory can be written to, probing it again if it couldn't last time
+    /// or a probe is due after `new_jobs` more jobs
+    fn check_builddir_writable(&self, new_jobs: u64) -> bool {
+        let jobs = self.jobs_since_probe.fetch_add(new_jobs, Ordering::Relaxed) + new_jobs;
+        if self.builddir_writable.load(Ordering::Relaxed) && jobs < WRITE_PROBE_INTERVAL_JOBS {
+            return true;
+        }
+
+        self.jobs_since_probe.store(0, Ordering::Relaxed);
+        let writable = probe_writable(&self.builddir, &self.health);
+        self.builddir_writable.store(writable, Ordering::Relaxed);
+        writable
+    }
+
+    async fn set_job_frozen(&self, processor: u32, frozen: bool) -> TonicResult<()> {
+        let cgroup_dir = self.jobs.get(processor).await.ok_or_else(|| {
+            Status::not_found(format!("No job with a cgroup on processor {processor}"))
+        })?;
+
+        set_frozen(&RealCgroupWriter, &cgroup_dir, frozen).map_err(|e| {
+            Status::failed_precondition(format!("Failed to set frozen = {frozen}: {e}"))
+        })?;
+
+        info!(processor, frozen, "Job freeze state changed");
+        Ok(())
+    }
+}
+
+/// Creates and removes a file in `builddir`, so a filesystem that went read-only, e.g. after a
+/// disk error, marks the runner not ready instead of failing every job with EROFS
+fn probe_writable(builddir: &Path, health: &Health) -> bool {
+    let probe = builddir.join(".write_test");
+    let res = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&probe)
+        .and_then(|_| std::fs::remove_file(&probe));
+    match res {
+        Ok(()) => {
+            health.set_healthy("build_directory");
+            true
+        }
+        Err(e) => {
+            error!("Build directory {:?} is not writable: {}", builddir, e);
+            health.set_unhealthy("build_directory", format!("{builddir:?} not writable: {e}"));
+            false
         }
     }
 }
 
+/// The job's resource usage, as RunResponse.resource_usage entries
+fn resource_usage(e: &ExitResources) -> Vec<PbAny> {
+    let mut usage = Vec::new();
+    let pbres: PosixResourceUsage = e.rusage.into();
+    if let Ok(r) = PbAny::from_msg(&pbres) {
+        usage.push(r);
+    };
+    if let Some(cpu_stat) = e.cpu_stat {
+        let pbcpu: CgroupCpuResourceUsage = cpu_stat.into();
+        if let Ok(r) = PbAny::from_msg(&pbcpu) {
+            usage.push(r);
+        };
+    }
+    if let Some(pbcgroup) = e.cgroup_usage() {
+        if let Ok(r) = PbAny::from_msg(&pbcgroup) {
+            usage.push(r);
+        };
+    }
+    usage
+}
+
 #[tonic::async_trait]
 impl Runner for RunnerService {
     #[tracing::instrument(skip_all)]
@@ -68,6 +393,12 @@ impl Runner for RunnerService {
         let readyreq = request.get_ref();
 
         debug!("CheckReadiness = {:?}", request);
+        self.check_builddir_writable(0);
+
+        if let Err(reason) = self.health.status() {
+            info!("CheckReadiness unhealthy = {}", reason);
+            return Err(Status::unavailable(format!("not ready: {reason}")));
+        }
 
         if self.builddir.join(&readyreq.path).exists() {
             info!("CheckReadiness.path exists = {:?}", readyreq.path);
@@ -94,24 +425,104 @@ impl Runner for RunnerService {
         if let Some(conn_info) = exts.get::<UdsConnectInfo>() {
             debug!("Run Connection Info = {:?}", conn_info);
         }
+        if !self.check_builddir_writable(1) {
+            return Err(Status::unavailable("build directory is not writable"));
+        }
+        let timeout = match run.timeout.map(Duration::try_from).transpose() {
+            Ok(timeout) => timeout.filter(|t| !t.is_zero()),
+            Err(_) => return Err(Status::invalid_argument("timeout must not be negative")),
+        };
 
         // If RPC is cancelled, this task is dropped immediately, must spawn child in a
         // separate task to be able to kill & reap child
         let token = CancellationToken::new();
         let _cancel_guard = token.clone().drop_guard();
         let procque = self.processors.clone();
+        let nproc = self.nproc;
+        let jobs = self.jobs.clone();
         let builddir = self.builddir.clone();
+        let child_cfg = self.config.clone();
+        let audit = self.audit.clone();
+        let event_tx = self.event_tx.clone();
 
         let childtask: JoinHandle<TonicResult<ExitResources>> = tokio::spawn(async move {
-            let processor = procque.take_cpu().await?;
-            let mut child = spawn_child(processor, builddir, &run)?;
-            let pid = child.id();
-            debug!("Started process: {} job {}", pid, processor);
+            let job_id = next_job_id();
+            emit(event_tx.as_ref(), job_id, JobEventKind::Queued);
+            let slot = procque.take_cpu()?;
+            let processor = slot.processor();
+            emit(event_tx.as_ref(), job_id, JobEventKind::Dequeued);
+            let exit_resuse = match spawn_child(
+                processor,
+                processor % nproc,
+                &builddir,
+                &child_cfg,
+                &run,
+            )
+            .await
+            {
+                Ok(SpawnResult {
+                    mut child,
+                    cgroup_dir,
+                }) => {
+                    let pid = child.id();
+                    debug!("Started process: {} job {}", pid, processor);
+                    emit(
+                        event_tx.as_ref(),
+                        job_id,
+                        JobEventKind::Started { pid, processor },
+                    );
+                    if let Some(ref cgroup_dir) = cgroup_dir {
+                        jobs.insert(processor, job_id, cgroup_dir.clone()).await;
+                    }
+
+                    let exit_resuse = wait_child(
+                        &mut child,
+                        cgroup_dir.as_deref(),
+                        &builddir,
+                        token,
+                        timeout,
+                        &child_cfg,
+                    )
+                    .await;
+                    info!("\nChild {} exit = {:#?}", pid, exit_resuse);
+                    jobs.remove(processor).await;
+
+                    if let Ok(ref e) = exit_resuse {
+                        let finished = JobEventKind::Finished {
+                            exit_reason: e.reason(),
+                            resources: *e,
+                        };
+                        emit(event_tx.as_ref(), job_id, finished);
+                        if let Some(signal) = e.status.signal() {
+                            emit(event_tx.as_ref(), job_id, JobEventKind::Killed { signal });
+                        }
+                    }
+
+                    if let (Some(hook), Ok(exit)) =
+                        (&child_cfg.sandbox.post_exec_hook, exit_resuse.as_ref())
+                    {
+                        let timeout =
+                            Duration::from_secs(child_cfg.sandbox.post_exec_hook_timeout_secs);
+                        run_post_exec_hook(hook, timeout, &builddir, &run, exit).await;
+                    }
 
-            let exit_resuse = wait_child(&mut child, token).await;
-            info!("\nChild {} exit = {:#?}", pid, exit_resuse);
+                    if let Some(audit) = audit {
+                        let entry = AuditEntry {
+                            pid,
+                            processor,
+                            command: &run.arguments,
+                            working_directory: &run.working_directory,
+                            exit: exit_resuse.as_ref().ok(),
+                        };
+                        audit.record(&entry).await;
+                    }
+                    exit_resuse
+                }
+                Err(e) => Err(e),
+            };
 
-            procque.give_cpu(processor).await;
+            // The slot is handed back when it goes out of scope, even if the child never started
+            drop(slot);
             exit_resuse
         });
 
@@ -119,23 +530,350 @@ impl Runner for RunnerService {
             .await
             .map_err(|_| Status::internal("No Exit Code"))?;
 
-        let exit_code = match exit_resuse {
-            Ok(ref e) => e.status.code(),
-            Err(_) => Some(255),
-        };
-
+        // A job that failed before it ran, or couldn't be waited for, is an error of the call,
+        // with the details of whatever failed
+        let e = exit_resuse?;
         let mut runresp = RunResponse::default();
-        match exit_code {
-            Some(code) => runresp.exit_code = code,
-            None => return Err(Status::internal("No Exit Code")),
-        }
-        if let Ok(e) = exit_resuse {
-            let pbres = e.rusage.into();
-            if let Ok(r) = PbAny::from_msg::<PosixResourceUsage>(&pbres) {
-                runresp.resource_usage = vec![r];
-            };
+        if e.timed_out {
+            return Err(status_with_details(
+                Code::DeadlineExceeded,
+                "action timeout exceeded",
+                "JOB_TIMED_OUT",
+                &[],
+                resource_usage(&e),
+            ));
+        }
+        match (e.status.code(), e.status.signal()) {
+            (Some(code), _) => {
+                runresp.exit_code = code;
+                runresp.resource_usage = resource_usage(&e);
+            }
+            // Killed, by a timeout, cancellation or itself. What it used until then is still
+            // worth knowing.
+            (None, signal) => {
+                let signal = signal.unwrap_or(0).to_string();
+                return Err(status_with_details(
+                    Code::Internal,
+                    "No Exit Code",
+                    "JOB_KILLED",
+                    &[("signal", &signal)],
+                    resource_usage(&e),
+                ));
+            }
         }
 
         Ok(tonic::Response::new(runresp))
     }
+
+    #[tracing::instrument(skip_all, fields(processor = request.get_ref().processor))]
+    async fn freeze_job(
+        &self,
+        request: tonic::Request<FreezeJobRequest>,
+    ) -> TonicResult<tonic::Response<()>> {
+        self.set_job_frozen(request.get_ref().processor, true)
+            .await
+            .map(tonic::Response::new)
+    }
+
+    #[tracing::instrument(skip_all, fields(processor = request.get_ref().processor))]
+    async fn unfreeze_job(
+        &self,
+        request: tonic::Request<UnfreezeJobRequest>,
+    ) -> TonicResult<tonic::Response<()>> {
+        self.set_job_frozen(request.get_ref().processor, false)
+            .await
+            .map(tonic::Response::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::google::rpc::{ErrorInfo, RetryInfo, Status as RpcStatus};
+    use prost::Message;
+    use std::collections::HashSet;
+
+    fn service(slots: u32) -> (tempfile::TempDir, RunnerService) {
+        service_with(slots, Configuration::default())
+    }
+
+    fn service_with(slots: u32, mut config: Configuration) -> (tempfile::TempDir, RunnerService) {
+        let base = tempfile::tempdir().unwrap();
+        std::fs::create_dir(base.path().join("build")).unwrap();
+        config.server.max_concurrent_jobs = Some(slots);
+        let svc = RunnerService::new(base.path(), 1, Arc::new(config), Health::default());
+        (base, svc)
+    }
+
+    #[tokio::test]
+    async fn run_with_full_queue_returns_retry_info() {
+        let (_base, svc) = service(1);
+        let queue = svc.processor_queue();
+        // A first lease, so there is a mean to base the retry delay on
+        drop(queue.take_cpu().unwrap());
+        let _slot = queue.take_cpu().unwrap();
+
+        let status = svc
+            .run(tonic::Request::new(RunRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+
+        let details = RpcStatus::decode(status.details()).unwrap();
+        let info: ErrorInfo = details.details[0].to_msg().unwrap();
+        assert_eq!(info.reason, "NO_FREE_SLOTS");
+        let retry: RetryInfo = details.details[1].to_msg().unwrap();
+        assert!(retry.retry_delay.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn contended_slots_all_come_back() {
+        const SLOTS: u32 = 4;
+        let queue = ProcessorQueue::new((0..SLOTS).collect());
+        let held = Arc::new(std::sync::Mutex::new(HashSet::new()));
+
+        let tasks: Vec<_> = (0..32)
+            .map(|_| {
+                let queue = queue.clone();
+                let held = held.clone();
+                tokio::spawn(async move {
+                    for _ in 0..50 {
+                        let guard = match queue.take_cpu() {
+                            Ok(guard) => guard,
+                            Err(status) => {
+                                assert_eq!(status.code(), Code::ResourceExhausted);
+                                tokio::task::yield_now().await;
+                                continue;
+                            }
+                        };
+                        let slot = guard.processor();
+                        assert!(held.lock().unwrap().insert(slot), "slot {slot} taken twice");
+                        tokio::task::yield_now().await;
+                        assert!(held.lock().unwrap().remove(&slot));
+                        drop(guard);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(queue.taken(), 0);
+        let mut free: Vec<u32> = queue.lock().free.iter().copied().collect();
+        free.sort_unstable();
+        assert_eq!(free, (0..SLOTS).collect::<Vec<_>>());
+        tokio::time::timeout(Duration::from_secs(1), queue.wait_for_drain())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_of_running_jobs_is_sampled() {
+        let dir = tempfile::tempdir().unwrap();
+        let running = dir.path().join("job0");
+        std::fs::create_dir(&running).unwrap();
+        std::fs::write(running.join("memory.current"), "8388608\n").unwrap();
+        let jobs = ActiveJobs::default();
+        jobs.insert(0, 17, running).await;
+        // Finished and cleaned up just before it was sampled
+        jobs.insert(1, 18, dir.path().join("job1")).await;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        sample_job_memory(&jobs, CgroupVersion::V2, &tx).await;
+        drop(tx);
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.job_id, 17);
+        assert!(
+            matches!(
+                event.kind,
+                JobEventKind::MemorySampled {
+                    processor: 0,
+                    bytes: 8388608
+                }
+            ),
+            "{event:?}"
+        );
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn memory_sampler_needs_an_event_sink() {
+        let (_base, svc) = service(1);
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            svc.memory_sampler(Duration::from_secs(1)),
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Prints what a take_cpu and drop pair costs as more tasks contend for the slots, nothing
+    /// to assert on a shared machine. Run with
+    /// `cargo test --release take_cpu_timing -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore]
+    async fn take_cpu_timing() {
+        const SLOTS: u32 = 16;
+        const ROUNDS: u32 = 10_000;
+
+        for tasks in [1, 4, 16, 64] {
+            let queue = ProcessorQueue::new((0..SLOTS).collect());
+            let start = std::time::Instant::now();
+            let handles: Vec<_> = (0..tasks)
+                .map(|_| {
+                    let queue = queue.clone();
+                    tokio::spawn(async move {
+                        let mut rejected = 0;
+                        let mut taken = 0;
+                        while taken < ROUNDS {
+                            match queue.take_cpu() {
+                                Ok(guard) => {
+                                    // Held across a yield, or the slots are never contended
+                                    tokio::task::yield_now().await;
+                                    drop(guard);
+                                    taken += 1;
+                                }
+                                Err(_) => {
+                                    rejected += 1;
+                                    tokio::task::yield_now().await;
+                                }
+                            }
+                        }
+                        rejected
+                    })
+                })
+                .collect();
+            let mut rejected = 0;
+            for handle in handles {
+                rejected += handle.await.unwrap();
+            }
+            let elapsed = start.elapsed();
+
+            println!(
+                "{tasks:>2} tasks: {:?} per take_cpu and drop, {rejected} rejected",
+                elapsed / (tasks * ROUNDS)
+            );
+            assert_eq!(queue.taken(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_for_drain_resolves_when_the_last_slot_comes_back() {
+        let queue = ProcessorQueue::new((0..2).collect());
+        let first = queue.take_cpu().unwrap();
+        let second = queue.take_cpu().unwrap();
+        queue.close();
+
+        let waiter = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.wait_for_drain().await }
+        });
+        drop(first);
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        drop(second);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn slot_comes_back_when_the_job_task_panics() {
+        let queue = ProcessorQueue::new((0..1).collect());
+        let guard = queue.take_cpu().unwrap();
+
+        let task = tokio::spawn(async move {
+            let _guard = guard;
+            panic!("job task failed");
+        });
+        assert!(task.await.unwrap_err().is_panic());
+
+        assert_eq!(queue.taken(), 0);
+        assert_eq!(queue.take_cpu().unwrap().processor(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_setup_gives_the_slot_back() {
+        let mut config = Configuration::default();
+        // Longer than HOST_NAME_MAX, pid1 fails to set it if nothing failed before
+        config.sandbox.sandbox_hostname = "x".repeat(100);
+        let (_base, svc) = service_with(1, config);
+
+        let request = RunRequest {
+            arguments: vec!["/bin/true".to_string()],
+            stdout_path: "stdout".to_string(),
+            stderr_path: "stderr".to_string(),
+            ..RunRequest::default()
+        };
+        let status = svc.run(tonic::Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), Code::Internal, "{status:?}");
+
+        assert_eq!(svc.processor_queue().taken(), 0);
+        // The job's task runs on this thread, so would the zombie of pid1
+        assert_eq!(crate::child::children_of_this_thread(), "");
+    }
+
+    /// End-to-end runs in a cgroup, they need cgroup v2 with the memory controller enabled for
+    /// /sys/fs/cgroup/bb_runner. That takes root or a delegated cgroup, so CI doesn't run them,
+    /// only `cargo test --features integration` does.
+    #[cfg(feature = "integration")]
+    mod integration {
+        use super::*;
+        use crate::proto::resourceusage::CgroupResourceUsage;
+
+        /// `dd` allocates a buffer of `block_size` and fills it from /dev/zero
+        fn dd_request(block_size: &str) -> RunRequest {
+            let bs = format!("bs={block_size}");
+            RunRequest {
+                arguments: ["/bin/dd", "if=/dev/zero", "of=/dev/null", &bs, "count=1"]
+                    .map(String::from)
+                    .to_vec(),
+                stdout_path: "stdout".to_string(),
+                stderr_path: "stderr".to_string(),
+                ..RunRequest::default()
+            }
+        }
+
+        fn find<M: prost::Name + Default>(details: &[PbAny]) -> Option<M> {
+            details.iter().find_map(|d| d.to_msg().ok())
+        }
+
+        #[tokio::test]
+        async fn memory_peak_is_reported() {
+            let (_base, svc) = service(1);
+            let resp = svc
+                .run(tonic::Request::new(dd_request("16M")))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(resp.exit_code, 0);
+
+            let posix: PosixResourceUsage = find(&resp.resource_usage).unwrap();
+            assert!(posix.maximum_resident_set_size >= 10 * 1024 * 1024);
+            let cgroup: CgroupResourceUsage = find(&resp.resource_usage).unwrap();
+            assert!(cgroup.memory_peak_bytes >= 10 * 1024 * 1024);
+            assert_eq!(cgroup.oom_kill_count, 0);
+
+            // The default cleanup policy removes the cgroup of every job
+            assert!(!Path::new("/sys/fs/cgroup/bb_runner/job0").exists());
+        }
+
+        #[tokio::test]
+        async fn allocating_past_the_memory_limit_is_oom_killed() {
+            let (_base, svc) = service(1);
+            let status = svc
+                .run(tonic::Request::new(dd_request("1500M")))
+                .await
+                .unwrap_err();
+
+            let details = RpcStatus::decode(status.details()).unwrap();
+            let cgroup: CgroupResourceUsage =
+                find(&details.details).unwrap_or_else(|| panic!("not run in a cgroup: {status:?}"));
+            assert!(cgroup.oom_kill_count > 0, "{cgroup:?}");
+            assert!(!Path::new("/sys/fs/cgroup/bb_runner/job0").exists());
+        }
+    }
 }